@@ -10,20 +10,21 @@ fn create_xml<P:AsRef<Path>>(path:P) {
     // append first child
     let mut child1:ETreeNode = ETreeNode::new("CHILD-A");
     child1.set_attr("DEST", "CHN");
-    let child1_pos = tree.append_child_node(root_pos, child1).unwrap();
+    let child1_id = tree.append_child_node_id(root_pos, child1).unwrap();
     // append another child after first child
     let mut child2:ETreeNode = ETreeNode::new("CHILD-B");
     child2.set_text("Shanghail");
-    tree.append_next_node(child1_pos, child2);
+    tree.append_next_node(tree.pos_of(child1_id).unwrap(), child2);
     // append another child before first child
     let mut child3:ETreeNode = ETreeNode::new("CHILD-C");
     child3.set_attr("DEST", "CHN");
     child3.set_text("Shanghail");
-    tree.append_previous_node(child1_pos, child3);
+    tree.append_previous_node(tree.pos_of(child1_id).unwrap(), child3);
     // append a child in the first child
     let mut child4:ETreeNode = ETreeNode::new("SUBCHILD-A");
     child4.set_text("EAST");
-    let pos = tree.find("//CHILD-A").unwrap(); // after inserting child3, child1_pos becomes invaild
+    // position of child1 is changed after inserting child3, but its id is not
+    let pos = tree.pos_of(child1_id).unwrap();
     tree.append_child_node(pos, child4);
     tree.pretty("\n  ");
     tree.write_file(path).ok();
@@ -40,6 +41,12 @@ fn modify_xml<P:AsRef<Path>>(path_in:P, path_out:P) {
     // tree.append_next_tree(subtree_pos, subtree.clone());
     let parent_pos = tree.parent(subtree_pos).unwrap();
     tree.append_child_tree(parent_pos, subtree);
+    // `find` returns the first match only, `find_all` collects every match
+    for pos in tree.find_all("//CHILD-A/SUBCHILD-A") {
+        if let Some(node) = tree.node_mut(pos) {
+            node.set_attr("CHECKED", "yes");
+        }
+    }
     tree.write_file(path_out).ok();
 }
 
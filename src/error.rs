@@ -0,0 +1,68 @@
+use std::fmt;
+
+/// XPath syntax error
+///
+/// `position` is the byte offset in `path` where the parser stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct XPathError {
+    pub path:String,
+    pub position:usize,
+}
+
+impl fmt::Display for XPathError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid xpath {:?} at position {}", self.path, self.position)
+    }
+}
+
+impl std::error::Error for XPathError {}
+
+/// Element tree error
+#[derive(Debug, Clone, PartialEq)]
+pub enum ETreeError {
+    /// malformed XML reported by the reader at byte `position`
+    Xml { position:usize, message:String },
+    /// node at `index` of a flat list is deeper than one level below the previous element
+    InvalidDepth { index:usize, depth:usize },
+    /// node at byte `position` is nested deeper than `max_depth`
+    TooDeep { position:usize, max_depth:usize },
+    /// node at byte `position` exceeds the limit of `max_nodes` nodes
+    TooManyNodes { position:usize, max_nodes:usize },
+    /// close tag `found` doesn't match the open tag `expected`, `position` is the byte offset of its name
+    MismatchedTag { expected:String, found:String, position:usize },
+    /// open tag `name` is not closed before the end of input at byte `position`
+    UnclosedTag { name:String, position:usize },
+    /// route of node at `index` doesn't follow route of the previous node at `previous` or refers to a missing node,
+    /// `index` is the node count at the end of tree and then `route` is `#`
+    CorruptTree { previous:usize, previous_route:String, index:usize, route:String },
+}
+
+impl fmt::Display for ETreeError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ETreeError::Xml { position, message } => write!(f, "Error at position {}: {}", position, message),
+            ETreeError::InvalidDepth { index, depth } => write!(f, "Invalid depth {} of node {}", depth, index),
+            ETreeError::TooDeep { position, max_depth } => write!(f, "Error at position {}: depth exceeds {}", position, max_depth),
+            ETreeError::TooManyNodes { position, max_nodes } => write!(f, "Error at position {}: node count exceeds {}", position, max_nodes),
+            ETreeError::MismatchedTag { expected, found, position } => write!(f, "Error at position {}: expected </{}>, found </{}>", position, expected, found),
+            ETreeError::UnclosedTag { name, position } => write!(f, "Error at position {}: unclosed tag <{}>", position, name),
+            ETreeError::CorruptTree { previous, previous_route, index, route } => write!(f, "Corrupt tree: route {} of node {} doesn't follow route {} of node {}", route, index, previous_route, previous),
+        }
+    }
+}
+
+impl ETreeError {
+    /// move byte positions by `offset`, for errors of a part of the parsed text
+    pub(crate) fn shift(self, offset:usize) -> ETreeError {
+        match self {
+            ETreeError::Xml { position, message } => ETreeError::Xml { position:position + offset, message },
+            ETreeError::TooDeep { position, max_depth } => ETreeError::TooDeep { position:position + offset, max_depth },
+            ETreeError::TooManyNodes { position, max_nodes } => ETreeError::TooManyNodes { position:position + offset, max_nodes },
+            ETreeError::MismatchedTag { expected, found, position } => ETreeError::MismatchedTag { expected, found, position:position + offset },
+            ETreeError::UnclosedTag { name, position } => ETreeError::UnclosedTag { name, position:position + offset },
+            other => other,
+        }
+    }
+}
+
+impl std::error::Error for ETreeError {}
@@ -2,12 +2,154 @@ use std::fs;
 use std::path::Path;
 use std::io::prelude::*;
 use std::io::Cursor;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use quick_xml::{Reader, Writer};
 use quick_xml::events::{Event, BytesStart, BytesEnd, BytesText, BytesDecl};
 use regex::Regex;
+use once_cell::sync::Lazy;
 use super::xpath;
-use super::etreenode::ETreeNode;
+use super::etreenode::{ETreeNode, NodeId, Slot};
+use super::error::{ETreeError, XPathError};
+use super::options::{WriteOptions, ReadOptions};
+
+/// general entity declaration in DOCTYPE
+static ENTITY_DECL:Lazy<Regex> = Lazy::new(|| Regex::new(r#"<!ENTITY\s+(?P<name>[^\s%]+)\s+(?:"(?P<dquote>[^"]*)"|'(?P<squote>[^']*)')\s*>"#).unwrap());
+
+/// split route `#a#b#` into parent route `#a#` and idx `b` of current node
+fn split_route(route:&str) -> Option<(String, usize)> {
+    let body = route.strip_suffix('#')?;
+    let cut = body.rfind('#')?;
+    let current = body[cut+1..].parse().ok()?;
+    Some((route[..=cut].to_string(), current))
+}
+
+/// resolve URI `reference` against `base` as RFC 3986 does, `base` may be relative too
+fn join_uri(base:&str, reference:&str) -> String {
+    let scheme_len = |uri:&str| -> Option<usize> {
+        let colon = uri.find(':')?;
+        let scheme = &uri[..colon];
+        let valid = scheme.starts_with(|c:char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        if valid { Some(colon + 1) } else { None }
+    };
+    if scheme_len(reference).is_some() {
+        return reference.to_string();
+    }
+    let base = &base[..base.find('#').unwrap_or(base.len())];
+    if reference.is_empty() || reference.starts_with('#') {
+        return format!("{}{}", base, reference);
+    }
+    let base_path = &base[..base.find('?').unwrap_or(base.len())];
+    if reference.starts_with('?') {
+        return format!("{}{}", base_path, reference);
+    }
+    // base_path is split into scheme + authority and path
+    let mut head_len = scheme_len(base_path).unwrap_or(0);
+    if base_path[head_len..].starts_with("//") {
+        if reference.starts_with("//") {
+            return format!("{}{}", &base_path[..head_len], reference);
+        }
+        head_len += 2 + base_path[head_len+2..].find('/').unwrap_or(base_path.len() - head_len - 2);
+    }
+    let (head, path) = base_path.split_at(head_len);
+    let merged = if reference.starts_with('/') {
+        reference.to_string()
+    } else if path.is_empty() && head.ends_with(|c| c != ':') {
+        format!("/{}", reference)
+    } else {
+        format!("{}{}", &path[..path.rfind('/').map_or(0, |x| x + 1)], reference)
+    };
+    // remove dot segments of the path, query and fragment are kept as they are
+    let cut = merged.find(['?', '#']).unwrap_or(merged.len());
+    let mut segments:Vec<&str> = Vec::new();
+    let mut parts = merged[..cut].split('/').peekable();
+    while let Some(part) = parts.next() {
+        let last = parts.peek().is_none();
+        match part {
+            "." => if last { segments.push("") },
+            ".." => {
+                if segments.len() > 1 || matches!(segments.first(), Some(x) if !x.is_empty()) {
+                    segments.pop();
+                }
+                if last {
+                    segments.push("");
+                }
+            },
+            _ => segments.push(part),
+        }
+    }
+    format!("{}{}{}", head, segments.join("/"), &merged[cut..])
+}
+
+/// escape `text` for writing, except entity references starting at byte offsets `refs`
+fn escape_text(text:&str, refs:&[usize]) -> String {
+    let escape = |x:&str| String::from_utf8(quick_xml::escape::escape(x.as_bytes()).into_owned()).unwrap();
+    let mut out = String::new();
+    let mut last = 0;
+    for &start in refs {
+        let end = match text.get(start..).and_then(|x| x.find(';')) {
+            Some(end) if start >= last => start + end + 1,
+            _ => continue,
+        };
+        out.push_str(&escape(&text[last..start]));
+        out.push_str(&text[start..end]);
+        last = end;
+    }
+    out.push_str(&escape(&text[last..]));
+    out
+}
+
+/// trim the part of a value between entity references, the first part at start and the last part at end
+fn trim_part(part:&str, first:bool, last:bool) -> String {
+    let part = if first { part.trim_start() } else { part };
+    if last { part.trim_end() } else { part }.to_string()
+}
+
+/// idx of the parent in route `#a#b#`, `None` for top level route `#`
+fn parent_idx(route:&str) -> Option<usize> {
+    route.trim_end_matches('#').rsplit('#').next().and_then(|x| x.parse().ok())
+}
+
+fn xml_error(reader:&Reader<&[u8]>, e:quick_xml::Error) -> ETreeError {
+    ETreeError::Xml { position:reader.buffer_position(), message:e.to_string() }
+}
+
+/// idx of (parent, previous sibling, next sibling)
+type NodeLink = (Option<usize>, Option<usize>, Option<usize>);
+
+/// Event reported by `ETree::read_events`
+///
+/// node of `Start` and `Empty` has namespace, name and attrs, but no idx and route
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    /// open tag, text of the node is empty
+    Start(ETreeNode),
+    /// self-closing tag, the node has no text
+    Empty(ETreeNode),
+    /// close tag with the name of element
+    End(String),
+    /// text after open tag or close tag, references to entities declared in DOCTYPE are kept as `&name;`
+    Text(String),
+    Comment(String),
+    CData(String),
+    PI(String),
+    DocType(String),
+}
+
+/// Difference between two trees reported by `ETree::diff`
+///
+/// nodes are identified by `ETree::node_path`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffOp {
+    /// element only exists in the other tree
+    Added(String),
+    /// element only exists in this tree
+    Removed(String),
+    /// text of element is changed from old to new
+    TextChanged(String, String, String),
+    /// attr of element is changed from old to new, `None` means the attr doesn't exist
+    AttrChanged(String, String, Option<String>, Option<String>),
+}
 
 /// Element tree
 ///
@@ -17,15 +159,42 @@ pub struct ETree {
     indent:String,
     count:usize,
     version:Vec<u8>,
+    declared:bool,
+    /// whitespace between parsed XML declaration and the first node, `crlf` is written if `None`
+    decl_tail:Option<String>,
     encoding:Option<Vec<u8>>,
     standalone:Option<Vec<u8>>,
     data:Vec<ETreeNode>,
     crlf:String,
     enable_index:bool,
     index:HashMap<usize, usize>,
+    links:HashMap<usize, NodeLink>,
+    resolve_entities:bool,
+    entities:HashMap<String, String>,
+    namespaces:HashMap<String, String>,
 }
 
 impl ETree {
+    /// tree without nodes and with default settings, constructors override fields of it
+    fn empty() -> ETree {
+        ETree {
+            indent:"".to_string(),
+            count:0,
+            version:"1.0".to_string().into_bytes(),
+            declared:false,
+            decl_tail:None,
+            encoding:None,
+            standalone:None,
+            data:Vec::new(),
+            crlf:"".to_string(),
+            enable_index:true,
+            index:HashMap::new(),
+            links:HashMap::new(),
+            resolve_entities:false,
+            entities:HashMap::new(),
+            namespaces:HashMap::new(),
+        }
+    }
     #[allow(dead_code)]
     pub fn parse_file<P:AsRef<Path>>(path:P) -> ETree {
         let mut fh = fs::OpenOptions::new().read(true).open(path).expect(
@@ -37,29 +206,214 @@ impl ETree {
     }
     #[allow(dead_code)]
     pub fn parse_str(content:&str) -> ETree {
+        match ETree::parse_str_checked(content) {
+            Ok(tree) => tree,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[allow(dead_code)]
+    /// parse `content` and report malformed XML as an error instead of panicking
+    pub fn parse_str_checked(content:&str) -> Result<ETree, ETreeError> {
+        ETree::parse_with(content, &ReadOptions::default())
+    }
+    #[allow(dead_code)]
+    /// parse a fragment which may have several top level nodes or no element at all, an XML declaration is an error
+    ///
+    /// top level nodes are stored as a flat sequence of siblings, `root` is the first element of them,
+    /// appending the fragment with `append_*_tree` inserts all of them
+    pub fn parse_fragment(content:&str) -> Result<ETree, ETreeError> {
+        let tree = ETree::parse_str_checked(content)?;
+        if tree.declared {
+            // find the declaration, malformed XML is already reported above
+            let mut reader = Reader::from_str(content);
+            let mut buf = Vec::new();
+            loop {
+                let before = reader.buffer_position();
+                match reader.read_event(&mut buf) {
+                    Ok(Event::Decl(_)) => return Err(ETreeError::Xml { position:before, message:"XML declaration in fragment".to_string() }),
+                    Ok(Event::Eof) | Err(_) => break,
+                    _ => buf.clear(),
+                }
+            }
+        }
+        Ok(tree)
+    }
+    #[allow(dead_code)]
+    /// parse documents concatenated in `content`, e.g. records appended to a log
+    ///
+    /// a document ends with its root element, comments and PIs after the root element belong to the next document
+    /// unless an XML declaration starts it. Error positions are byte offsets in `content`
+    pub fn parse_many(content:&str) -> Result<Vec<ETree>, ETreeError> {
+        let mut reader = Reader::from_str(content);
+        // documents are only split here, errors are reported by parsing each of them
+        reader.check_end_names(false);
+        let mut buf = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+        // end of the root element of the document being read
+        let mut root_end = None;
+        let mut out = Vec::new();
+        let mut parse = |from:usize, to:usize| -> Result<(), ETreeError> {
+            out.push(ETree::parse_str_checked(&content[from..to]).map_err(|e| e.shift(from))?);
+            Ok(())
+        };
+        loop {
+            let before = reader.buffer_position();
+            let before = before + content[before..].find('<').unwrap_or(0);
+            let event = reader.read_event(&mut buf).map_err(|e| xml_error(&reader, e))?;
+            let split = match event {
+                Event::Decl(_) => root_end.take().map(|_| before),
+                Event::Start(_) | Event::Empty(_) if depth == 0 => root_end.take(),
+                Event::Eof => break,
+                _ => None,
+            };
+            if let Some(end) = split {
+                parse(start, end)?;
+                start = end;
+            }
+            match event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                _ => {},
+            }
+            if depth == 0 && matches!(event, Event::End(_) | Event::Empty(_)) {
+                root_end = Some(reader.buffer_position());
+            }
+            buf.clear();
+        }
+        if !content[start..].trim().is_empty() {
+            parse(start, content.len())?;
+        }
+        Ok(out)
+    }
+    #[allow(dead_code)]
+    /// parse `content` and discard whitespace-only text between elements
+    ///
+    /// elements with non-whitespace text or `xml:space="preserve"` keep their whitespace
+    pub fn parse_str_trimmed(content:&str) -> Result<ETree, ETreeError> {
+        ETree::parse_with(content, &ReadOptions::new().trim(true))
+    }
+    #[allow(dead_code)]
+    /// parse untrusted `content`, fail when nodes are nested deeper than `max_depth` or there are more than `max_nodes` nodes
+    ///
+    /// top level nodes have depth 1
+    pub fn parse_str_limited(content:&str, max_depth:usize, max_nodes:usize) -> Result<ETree, ETreeError> {
+        ETree::parse_with(content, &ReadOptions::new().max_depth(max_depth).max_nodes(max_nodes))
+    }
+    #[allow(dead_code)]
+    /// parse `content` with `opts`
+    pub fn parse_with(content:&str, opts:&ReadOptions) -> Result<ETree, ETreeError> {
         let fileformat = if content.contains("\r\n") {
             "\r\n"
         } else {
             "\n"
         };
         let mut out = ETree {
-            indent:"".to_string(),
-            count:0,
-            version: Vec::new(),
-            encoding: None,
-            standalone: None,
-            data: Vec::new(),
-            crlf: fileformat.to_string(),
-            enable_index: false,
-            index: HashMap::new(),
+            version:Vec::new(),
+            crlf:fileformat.to_string(),
+            ..ETree::empty()
         };
-        out.read(content);
+        // references are replaced while reading, so they are never mixed with literal text
+        out.resolve_entities = opts.resolve_entities;
+        out.read(content, opts)?;
+        out.generate_index();
         out.detect_indent();
-        out
+        if opts.trim {
+            out.trim_blank_text();
+        }
+        if let Some(encoding) = opts.encoding.as_deref() {
+            out.set_encoding(encoding);
+        }
+        Ok(out)
+    }
+    #[allow(dead_code)]
+    /// build a tree from nodes in document order with their depth (0 for top level nodes)
+    ///
+    /// panics if the depth is invalid, see `from_flat_checked`
+    pub fn from_flat(nodes:Vec<(usize, ETreeNode)>) -> ETree {
+        match ETree::from_flat_checked(nodes) {
+            Ok(tree) => tree,
+            Err(e) => panic!("{}", e),
+        }
     }
     #[allow(dead_code)]
+    /// build a tree from nodes in document order with their depth (0 for top level nodes)
+    ///
+    /// depth of a node should not be larger than the depth of the previous node plus one,
+    /// and comment, PI, CDATA and DOCTYPE nodes can't have children
+    pub fn from_flat_checked(nodes:Vec<(usize, ETreeNode)>) -> Result<ETree, ETreeError> {
+        let mut tree = ETree::empty();
+        // routes of the last node of each depth, and the route for its children
+        let mut routes:Vec<String> = vec!["#".to_string()];
+        for (index, (depth, mut node)) in nodes.into_iter().enumerate() {
+            if depth >= routes.len() || (depth + 1 == routes.len() && depth > 0 && tree.data[tree.data.len() - 1].is_special()) {
+                return Err(ETreeError::InvalidDepth { index, depth });
+            }
+            if depth + 1 == routes.len() && depth > 0 && tree.data[tree.data.len() - 1].get_text().is_none() {
+                // the previous node becomes a parent
+                let last = tree.data.len() - 1;
+                tree.data[last].set_text("");
+            }
+            routes.truncate(depth + 1);
+            node.set_idx(tree.count);
+            node.set_route(&routes[depth]);
+            routes.push(format!("{}{}#", routes[depth], tree.count));
+            tree.data.push(node);
+            tree.count += 1;
+        }
+        tree.generate_index();
+        Ok(tree)
+    }
+    #[allow(dead_code)]
+    /// build a tree of single root `node` which declares `namespaces` of (prefix, uri), `""` prefix for the default namespace
+    ///
+    /// declarations are written as `xmlns`/`xmlns:prefix` attrs of the root and prefixes are registered by `register_namespace`,
+    /// so nodes built with `ETreeNode::set_namespace` only are written with the declared prefix
+    pub fn with_root(mut node:ETreeNode, namespaces:&[(&str, &str)]) -> ETree {
+        for (prefix, uri) in namespaces {
+            if prefix.is_empty() {
+                node.set_attr("xmlns", uri);
+            } else {
+                node.set_attr(&format!("xmlns:{}", prefix), uri);
+            }
+        }
+        let mut tree = ETree::from(node);
+        for (prefix, uri) in namespaces {
+            tree.register_namespace(prefix, uri);
+        }
+        tree
+    }
+    #[allow(dead_code)]
+    /// write the tree into file, a corrupt tree is reported as `std::io::ErrorKind::InvalidData`
     pub fn write_file<P:AsRef<Path>>(&self, path:P) -> std::io::Result<()> {
-        fs::write(path, self.write())
+        self.write_file_with(path, &WriteOptions::default())
+    }
+    #[allow(dead_code)]
+    /// write the tree into file with `opts`, the tree itself is not changed
+    ///
+    /// a corrupt tree is reported as `std::io::ErrorKind::InvalidData`
+    pub fn write_file_with<P:AsRef<Path>>(&self, path:P, opts:&WriteOptions) -> std::io::Result<()> {
+        let data = self.write_with(opts).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)
+    }
+    #[allow(dead_code)]
+    /// serialize the tree with `opts`, the tree itself is not changed
+    ///
+    /// routes broken by manual edits are reported as `ETreeError::CorruptTree`
+    pub fn write_with(&self, opts:&WriteOptions) -> Result<Vec<u8>, ETreeError> {
+        if opts.indent.is_none() && opts.line_ending.is_none() {
+            return self.write_nodes(opts);
+        }
+        let mut tree = self.clone();
+        match (opts.indent.as_deref(), opts.indent_cap) {
+            (Some(indent), Some(max_depth)) => tree.pretty_capped(indent, max_depth),
+            (Some(indent), None) => tree.pretty(indent),
+            _ => {},
+        }
+        if let Some(crlf) = opts.line_ending.as_deref() {
+            tree.set_line_ending(crlf);
+        }
+        tree.write_nodes(opts)
     }
     #[allow(dead_code)]
     /// get whether index feature is enabled
@@ -67,12 +421,92 @@ impl ETree {
         self.enable_index
     }
     #[allow(dead_code)]
-    /// set whether index feature is enabled (usable for function `pos()`)
+    /// set whether index feature is enabled (usable for function `pos()`, `parent()`, `previous()` and `next()`)
+    ///
+    /// index is enabled by default and kept up to date by every edit, without it `pos()` is O(n)
     pub fn set_enable_index(&mut self, enable_index:bool) {
         self.enable_index = enable_index;
         self.generate_index();
     }
     #[allow(dead_code)]
+    /// register `prefix` for namespace `uri`, used by `find_ns_iter`
+    pub fn register_namespace(&mut self, prefix:&str, uri:&str) {
+        self.namespaces.insert(prefix.to_string(), uri.to_string());
+    }
+    #[allow(dead_code)]
+    /// get namespace uri registered for `prefix`
+    pub fn namespace_uri(&self, prefix:&str) -> Option<&str> {
+        self.namespaces.get(prefix).map(|x| x.as_str())
+    }
+    #[allow(dead_code)]
+    /// get namespaces in scope of the node of specified position (prefix to uri, `""` for the default namespace),
+    /// `xmlns` attrs of the node and its ancestors override namespaces registered by `register_namespace`
+    pub fn in_scope_namespaces(&self, pos:usize) -> HashMap<String, String> {
+        let mut out = self.namespaces.clone();
+        let mut chain = Vec::new();
+        let mut current = if pos < self.data.len() { Some(pos) } else { None };
+        while let Some(x) = current {
+            chain.push(x);
+            current = self.parent(x);
+        }
+        for x in chain.into_iter().rev() {
+            for (key, value) in self.data[x].get_attr_iter() {
+                if key == "xmlns" {
+                    out.insert("".to_string(), value.clone());
+                } else if let Some(prefix) = key.strip_prefix("xmlns:") {
+                    out.insert(prefix.to_string(), value.clone());
+                }
+            }
+        }
+        out
+    }
+    #[allow(dead_code)]
+    /// get whether entities declared in DOCTYPE are resolved
+    pub fn get_resolve_entities(&self) -> bool {
+        self.resolve_entities
+    }
+    #[allow(dead_code)]
+    /// set whether entities declared in DOCTYPE are resolved
+    ///
+    /// General entities declared in the internal subset of DOCTYPE (e.g. `<!ENTITY foo "bar">`) are always collected while parsing.
+    /// By default references to them (e.g. `&foo;`) are kept as they are in text, tail and attribute values, and are written back unchanged.
+    /// Turning resolution on replaces the references with the declared values in the whole tree.
+    /// Turning it off again does not restore the references.
+    pub fn set_resolve_entities(&mut self, resolve_entities:bool) {
+        self.resolve_entities = resolve_entities;
+        if resolve_entities {
+            for node in self.data.iter_mut() {
+                node.resolve_entity_refs(&self.entities);
+            }
+        }
+    }
+    #[allow(dead_code)]
+    /// get indent detected from the document or set by `pretty`
+    pub fn indent_unit(&self) -> &str {
+        &self.indent
+    }
+    #[allow(dead_code)]
+    /// get line ending detected from the document or set by `pretty`
+    pub fn line_ending(&self) -> &str {
+        &self.crlf
+    }
+    #[allow(dead_code)]
+    /// set line ending to one of `"\n"`, `"\r\n"` and `"\r"`, line endings in all texts and tails are converted
+    ///
+    /// other values are ignored
+    pub fn set_line_ending(&mut self, crlf:&str) {
+        if !["\n", "\r\n", "\r"].contains(&crlf) {
+            return;
+        }
+        let convert = |x:&str| x.replace("\r\n", "\n").replace('\r', "\n").replace('\n', crlf);
+        for node in self.data.iter_mut() {
+            node.map_literal(&Slot::Tail, |x, _, _| convert(x));
+            node.map_literal(&Slot::Text, |x, _, _| convert(x));
+        }
+        self.decl_tail = self.decl_tail.as_deref().map(convert);
+        self.crlf = crlf.to_string();
+    }
+    #[allow(dead_code)]
     /// get XML version
     pub fn get_version(&self) -> Option<String> {
         String::from_utf8(self.version.clone()).ok()
@@ -81,6 +515,14 @@ impl ETree {
     /// set XML version
     pub fn set_version(&mut self, version:&str) {
         self.version = version.to_string().into_bytes();
+        self.declared = true;
+    }
+    #[allow(dead_code)]
+    /// check whether the source has XML declaration or version, encoding or standalone is set
+    ///
+    /// trees built from nodes have the default version "1.0" but no declaration
+    pub fn has_declaration(&self) -> bool {
+        self.declared
     }
     #[allow(dead_code)]
     /// get XML encoding
@@ -91,6 +533,7 @@ impl ETree {
     /// set XML encoding
     pub fn set_encoding(&mut self, encoding:&str) {
         self.encoding = Some(encoding.to_string().into_bytes());
+        self.declared = true;
     }
     #[allow(dead_code)]
     /// get XML standalone
@@ -101,28 +544,29 @@ impl ETree {
     /// set XML standalone
     pub fn set_standalone(&mut self, standalone:&str) {
         self.standalone = Some(standalone.to_string().into_bytes());
+        self.declared = true;
     }
     #[allow(dead_code)]
     /// get position of root node
+    ///
+    /// comment, PI and DOCTYPE nodes before the root element are skipped, the length of tree is returned if there is no element
     pub fn root(&self) -> usize {
-        let mut idx = 0;
-        while idx < self.data.len() {
-            if !(self.data[idx].get_localname().starts_with("<") && self.data[idx].get_localname().ends_with(">")) {
-                break;
-            }
-            idx += 1;
-        }
-        idx
+        self.data.iter().position(|x| !x.is_special()).unwrap_or(self.data.len())
+    }
+    #[allow(dead_code)]
+    /// get root element, `None` if there is no element
+    pub fn root_node(&self) -> Option<&ETreeNode> {
+        self.data.get(self.root())
     }
     #[allow(dead_code)]
     /// get position of parent node
     pub fn parent(&self, pos:usize) -> Option<usize> {
-        if pos <= 0 || pos >= self.data.len() {
+        if pos == 0 || pos >= self.data.len() {
             None
+        } else if self.enable_index {
+            self.links.get(&self.data[pos].get_idx()).and_then(|x| x.0).and_then(|x| self.pos(x))
         } else {
-            let close_tag = Regex::new(r"^(?P<parent>#.*?)(?P<current>\d+)#$").unwrap();
-            if let Some(c) = close_tag.captures(&self.data[pos].get_route()) {
-                let route = c.name("parent").unwrap().as_str();
+            if let Some((route, _)) = split_route(&self.data[pos].get_route()) {
                 let mut pos2 = pos;
                 while pos2 > 0 {
                     pos2 -= 1;
@@ -137,19 +581,25 @@ impl ETree {
     #[allow(dead_code)]
     /// get positions of children node
     pub fn children(&self, pos:usize) -> Vec<usize> {
-        let mut out:Vec<usize> = Vec::new();
-        if pos < self.data.len() {
-            let route = format!("{}{}#", self.data[pos].get_route(), self.data[pos].get_idx());
-            for i in pos+1..self.data.len() {
-                let curroute = self.data[i].get_route();
-                if curroute == route {
-                    out.push(i);
-                } else if !curroute.starts_with(&route) {
-                    break;
-                }
-            }
-        }
-        out
+        self.children_iter(pos).collect()
+    }
+    #[allow(dead_code)]
+    /// get positions of children elements, comment, CDATA, PI and DOCTYPE nodes are skipped
+    pub fn children_elements(&self, pos:usize) -> Vec<usize> {
+        self.children_iter(pos).filter(|&i| !self.data[i].is_special()).collect()
+    }
+    #[allow(dead_code)]
+    /// iterate positions of children node without collecting them
+    pub fn children_iter(&self, pos:usize) -> impl Iterator<Item=usize> + '_ {
+        let (start, route) = match self.data.get(pos) {
+            Some(node) => (pos + 1, format!("{}{}#", node.get_route(), node.get_idx())),
+            None => (self.data.len(), "".to_string()),
+        };
+        // descendants are contiguous and start with the route, children are those with exactly the route
+        let len = route.len();
+        (start..self.data.len())
+            .take_while(move |&i| self.data[i].route_ref().starts_with(route.as_str()))
+            .filter(move |&i| self.data[i].route_ref().len() == len)
     }
     #[allow(dead_code)]
     /// get positions of children node with specified name
@@ -163,6 +613,39 @@ impl ETree {
         out
     }
     #[allow(dead_code)]
+    /// get positions of children node with specified local name, prefixes are ignored
+    pub fn children_by_localname(&self, pos:usize, local:&str) -> Vec<usize> {
+        self.children_iter(pos).filter(|&x| self.data[x].get_localname() == local).collect()
+    }
+    #[allow(dead_code)]
+    /// get positions of children node with specified namespace uri and local name, `""` uri for no namespace
+    pub fn children_by_qname(&self, pos:usize, uri:&str, local:&str) -> Vec<usize> {
+        self.children_iter(pos).filter(|&x| self.data[x].get_localname() == local && self.data[x].get_namespace() == uri).collect()
+    }
+    #[allow(dead_code)]
+    /// get position of the nearest ancestor with specified name, the node itself is not checked
+    pub fn closest(&self, pos:usize, tagname:&str) -> Option<usize> {
+        self.closest_by(pos, |node| node.get_name() == tagname)
+    }
+    #[allow(dead_code)]
+    /// get position of the nearest ancestor for which `predicate` returns true, the node itself is not checked
+    pub fn closest_by<F:Fn(&ETreeNode) -> bool>(&self, pos:usize, predicate:F) -> Option<usize> {
+        let mut current = self.parent(pos);
+        while let Some(x) = current {
+            if predicate(&self.data[x]) {
+                return Some(x);
+            }
+            current = self.parent(x);
+        }
+        None
+    }
+    #[allow(dead_code)]
+    /// get text of the first child node with specified name
+    pub fn child_text(&self, pos:usize, tagname:&str) -> Option<String> {
+        let child = *self.children_by_name(pos, tagname).first()?;
+        self.data[child].get_text()
+    }
+    #[allow(dead_code)]
     /// get positions of descendant node
     pub fn descendant(&self, pos:usize) -> Vec<usize> {
         let mut out:Vec<usize> = Vec::new();
@@ -181,8 +664,10 @@ impl ETree {
     #[allow(dead_code)]
     /// get position of previous sibling node
     pub fn previous(&self, pos:usize) -> Option<usize> {
-        if pos <= 0  || pos >= self.data.len() {
+        if pos == 0 || pos >= self.data.len() {
             None
+        } else if self.enable_index {
+            self.links.get(&self.data[pos].get_idx()).and_then(|x| x.1).and_then(|x| self.pos(x))
         } else {
             let mut pos2 = pos;
             let route = self.data[pos].get_route();
@@ -202,8 +687,10 @@ impl ETree {
     #[allow(dead_code)]
     /// get position of next sibling node
     pub fn next(&self, pos:usize) -> Option<usize> {
-        if pos >= self.data.len() - 1 {
+        if pos + 1 >= self.data.len() {
             None
+        } else if self.enable_index {
+            self.links.get(&self.data[pos].get_idx()).and_then(|x| x.2).and_then(|x| self.pos(x))
         } else {
             let mut pos2 = pos + 1;
             let route = self.data[pos].get_route();
@@ -221,7 +708,256 @@ impl ETree {
         }
     }
     #[allow(dead_code)]
-    /// get position by idx
+    /// check whether the element of specified position has child elements and also non-whitespace text
+    /// (its own text or tails of its children)
+    pub fn is_mixed_content(&self, pos:usize) -> bool {
+        if pos >= self.data.len() || self.data[pos].is_special() {
+            return false;
+        }
+        let children = self.children(pos);
+        children.iter().any(|&x| !self.data[x].is_special())
+            && (!self.data[pos].text_ref().unwrap_or("").trim().is_empty()
+                || children.iter().any(|&x| !self.data[x].tail_ref().trim().is_empty()))
+    }
+    #[allow(dead_code)]
+    /// get the base URI of the node of specified position by joining `xml:base` of itself and its ancestors,
+    /// `None` if none of them has `xml:base`
+    pub fn resolve_base(&self, pos:usize) -> Option<String> {
+        let mut bases = Vec::new();
+        let mut current = if pos < self.data.len() { Some(pos) } else { None };
+        while let Some(x) = current {
+            if let Some(base) = self.data[x].get_attr("xml:base") {
+                bases.push(base);
+            }
+            current = self.parent(x);
+        }
+        let mut bases = bases.into_iter().rev();
+        let first = bases.next()?;
+        Some(bases.fold(first, |base, x| join_uri(&base, &x)))
+    }
+    #[allow(dead_code)]
+    /// get `prefix:localname` of the node of specified position as written by `write`
+    ///
+    /// a node with namespace but without prefix, e.g. built by `ETreeNode::set_namespace` only, keeps no prefix if the namespace
+    /// is the default namespace in scope, otherwise it takes a prefix registered by `register_namespace` for the namespace
+    pub fn get_full_name(&self, pos:usize) -> Option<String> {
+        let node = self.data.get(pos)?;
+        let ns = node.get_namespace();
+        if ns.is_empty() || !node.get_namespace_abbrev().is_empty() || self.in_scope(pos, "xmlns", &[]).as_deref() == Some(ns.as_str()) {
+            return Some(node.get_name());
+        }
+        let mut prefixes:Vec<&String> = self.namespaces.iter().filter(|x| *x.1 == ns).map(|x| x.0).collect();
+        prefixes.sort();
+        match prefixes.first() {
+            Some(prefix) => Some(format!("{}:{}", prefix, node.get_localname())),
+            None => Some(node.get_name()),
+        }
+    }
+    #[allow(dead_code)]
+    /// get zero-based index of the node of specified position among children of its parent, `None` for top level nodes
+    pub fn sibling_index(&self, pos:usize) -> Option<usize> {
+        let parent = self.parent(pos)?;
+        self.children_iter(parent).position(|x| x == pos)
+    }
+    #[allow(dead_code)]
+    /// get count of children of the parent of the node of specified position, including the node itself
+    pub fn sibling_count(&self, pos:usize) -> Option<usize> {
+        let parent = self.parent(pos)?;
+        Some(self.children_iter(parent).count())
+    }
+    #[allow(dead_code)]
+    /// get positions of all siblings after the node of specified position
+    pub fn following_siblings(&self, pos:usize) -> Vec<usize> {
+        let mut out:Vec<usize> = Vec::new();
+        let mut current = pos;
+        while let Some(sibling) = self.next(current) {
+            out.push(sibling);
+            current = sibling;
+        }
+        out
+    }
+    #[allow(dead_code)]
+    /// get positions of all siblings before the node of specified position in document order
+    pub fn preceding_siblings(&self, pos:usize) -> Vec<usize> {
+        let mut out:Vec<usize> = Vec::new();
+        let mut current = pos;
+        while let Some(sibling) = self.previous(current) {
+            out.push(sibling);
+            current = sibling;
+        }
+        out.reverse();
+        out
+    }
+    #[allow(dead_code)]
+    /// get path of the element of specified position, e.g. `/root[1]/item[2]`
+    ///
+    /// each step is indexed among siblings with the same name, `None` for comment, PI, CDATA and DOCTYPE nodes
+    pub fn node_path(&self, pos:usize) -> Option<String> {
+        if pos >= self.data.len() || self.data[pos].is_special() {
+            return None;
+        }
+        let mut steps:Vec<String> = Vec::new();
+        let mut current = Some(pos);
+        while let Some(x) = current {
+            let name = self.data[x].get_name();
+            let mut n = 1;
+            let mut sibling = x;
+            while let Some(y) = self.previous(sibling) {
+                if self.data[y].get_name() == name {
+                    n += 1;
+                }
+                sibling = y;
+            }
+            steps.push(format!("/{}[{}]", name, n));
+            current = self.parent(x);
+        }
+        steps.reverse();
+        Some(steps.concat())
+    }
+    fn node_paths(&self) -> Vec<Option<String>> {
+        let mut out:Vec<Option<String>> = Vec::new();
+        let mut parent_path:HashMap<String, String> = HashMap::new();
+        let mut counter:HashMap<(String, String), usize> = HashMap::new();
+        parent_path.insert("#".to_string(), "".to_string());
+        for node in self.data.iter() {
+            if node.is_special() {
+                out.push(None);
+                continue;
+            }
+            let parent = parent_path.get(&node.get_route()).cloned().unwrap_or("".to_string());
+            let n = counter.entry((node.get_route(), node.get_name())).or_insert(0);
+            *n += 1;
+            let path = format!("{}/{}[{}]", parent, node.get_name(), n);
+            parent_path.insert(format!("{}{}#", node.get_route(), node.get_idx()), path.clone());
+            out.push(Some(path));
+        }
+        out
+    }
+    #[allow(dead_code)]
+    /// compare elements with `other` by `node_path`, text is compared without surrounding whitespace
+    ///
+    /// only the outermost added or removed element is reported
+    pub fn diff(&self, other:&ETree) -> Vec<DiffOp> {
+        let mut out:Vec<DiffOp> = Vec::new();
+        let paths = self.node_paths();
+        let other_paths = other.node_paths();
+        let other_map:HashMap<&String, usize> = other_paths.iter().enumerate().filter_map(|(i, x)| x.as_ref().map(|x| (x, i))).collect();
+        let map:HashMap<&String, usize> = paths.iter().enumerate().filter_map(|(i, x)| x.as_ref().map(|x| (x, i))).collect();
+        let mut removed:Vec<String> = Vec::new();
+        for (pos, path) in paths.iter().enumerate() {
+            let path = match path {
+                Some(path) => path,
+                None => continue,
+            };
+            match other_map.get(path) {
+                None => {
+                    if !removed.iter().any(|x| path.starts_with(&format!("{}/", x))) {
+                        removed.push(path.clone());
+                        out.push(DiffOp::Removed(path.clone()));
+                    }
+                },
+                Some(&other_pos) => {
+                    let node = &self.data[pos];
+                    let other_node = &other.data[other_pos];
+                    let text = node.get_text().unwrap_or("".to_string()).trim().to_string();
+                    let other_text = other_node.get_text().unwrap_or("".to_string()).trim().to_string();
+                    if text != other_text {
+                        out.push(DiffOp::TextChanged(path.clone(), text, other_text));
+                    }
+                    let mut keys:Vec<String> = node.get_attr_iter().map(|x| x.0.clone()).collect();
+                    for (key, _) in other_node.get_attr_iter() {
+                        if !keys.contains(key) {
+                            keys.push(key.clone());
+                        }
+                    }
+                    for key in keys {
+                        let value = node.get_attr(&key);
+                        let other_value = other_node.get_attr(&key);
+                        if value != other_value {
+                            out.push(DiffOp::AttrChanged(path.clone(), key, value, other_value));
+                        }
+                    }
+                },
+            }
+        }
+        let mut added:Vec<String> = Vec::new();
+        for path in other_paths.iter().flatten() {
+            if !map.contains_key(path) && !added.iter().any(|x| path.starts_with(&format!("{}/", x))) {
+                added.push(path.clone());
+                out.push(DiffOp::Added(path.clone()));
+            }
+        }
+        out
+    }
+    #[allow(dead_code)]
+    /// run `rule` on every node and collect the position and message of each violation
+    pub fn validate<F:FnMut(&ETree, usize) -> Option<String>>(&self, mut rule:F) -> Vec<(usize, String)> {
+        (0..self.data.len()).filter_map(|pos| rule(self, pos).map(|msg| (pos, msg))).collect()
+    }
+    #[allow(dead_code)]
+    /// check internal bookkeeping, e.g. after manual edits with `node_mut`, and describe each broken invariant
+    ///
+    /// - idx is unique and less than the idx counter
+    /// - route of each node is `#` or the route of its parent extended by the idx of the parent, and the parent is
+    ///   an element placed before the node
+    /// - nodes are in document order: a node is a sibling or a child of the previous node, or a sibling of one of its ancestors
+    /// - index of idx to position matches the actual positions if index is enabled
+    pub fn check_integrity(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        let mut seen:HashMap<usize, usize> = HashMap::new();
+        for (pos, node) in self.data.iter().enumerate() {
+            let idx = node.get_idx();
+            if let Some(first) = seen.insert(idx, pos) {
+                errors.push(format!("node {}: idx {} is already used by node {}", pos, idx, first));
+                seen.insert(idx, first);
+            }
+            if idx >= self.count {
+                errors.push(format!("node {}: idx {} is not less than count {}", pos, idx, self.count));
+            }
+            let route = node.get_route();
+            if route != "#" {
+                match split_route(&route) {
+                    Some((parent_route, parent_idx)) => {
+                        match seen.get(&parent_idx).filter(|&&x| x < pos) {
+                            Some(&parent) if self.data[parent].is_special() => errors.push(format!("node {}: parent {} is not an element", pos, parent)),
+                            Some(&parent) if self.data[parent].get_route() != parent_route => {
+                                errors.push(format!("node {}: route {} doesn't extend route {} of parent {}", pos, route, self.data[parent].get_route(), parent));
+                            },
+                            Some(_) => {},
+                            None => errors.push(format!("node {}: parent idx {} in route {} is not found before the node", pos, parent_idx, route)),
+                        }
+                    },
+                    None => errors.push(format!("node {}: malformed route {}", pos, route)),
+                }
+            }
+            if pos > 0 {
+                let previous = &self.data[pos - 1];
+                let previous_route = previous.get_route();
+                if route != previous_route && route != format!("{}{}#", previous_route, previous.get_idx()) && !previous_route.starts_with(&route) {
+                    errors.push(format!("node {}: route {} doesn't follow route {} of node {}", pos, route, previous_route, pos - 1));
+                }
+            }
+        }
+        if self.enable_index {
+            for (pos, node) in self.data.iter().enumerate() {
+                match self.index.get(&node.get_idx()) {
+                    Some(&x) if x == pos => {},
+                    Some(&x) => errors.push(format!("node {}: index maps idx {} to position {}", pos, node.get_idx(), x)),
+                    None => errors.push(format!("node {}: idx {} is missing in index", pos, node.get_idx())),
+                }
+            }
+            if self.index.len() > seen.len() {
+                errors.push(format!("index has {} entries for {} nodes", self.index.len(), seen.len()));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    #[allow(dead_code)]
+    /// get position by idx, O(1) with index and O(n) without index
     pub fn pos(&self, idx:usize) -> Option<usize> {
         if self.enable_index {
             self.index.get(&idx).copied()
@@ -235,6 +971,21 @@ impl ETree {
         }
     }
     #[allow(dead_code)]
+    /// get stable id of the node of specified position
+    pub fn id(&self, pos:usize) -> Option<NodeId> {
+        self.data.get(pos).map(|x| x.get_id())
+    }
+    #[allow(dead_code)]
+    /// get current position of the node with specified id
+    pub fn pos_of(&self, id:NodeId) -> Option<usize> {
+        self.pos(id.0)
+    }
+    #[allow(dead_code)]
+    /// get node by id
+    pub fn node_by_id(&self, id:NodeId) -> Option<&ETreeNode> {
+        self.pos_of(id).map(|x| &self.data[x])
+    }
+    #[allow(dead_code)]
     /// get node by position
     pub fn node(&self, pos:usize) -> Option<&ETreeNode> {
         self.data.get(pos)
@@ -245,32 +996,56 @@ impl ETree {
         self.data.get_mut(pos)
     }
     #[allow(dead_code)]
-    /// clone a subtree rooted at the node of specified position
-    pub fn subtree(&self, pos:usize) -> ETree {
+    /// clone the node of specified position without its children, idx and route are reset so that it can be appended again
+    pub fn detach_node(&self, pos:usize) -> Option<ETreeNode> {
+        let mut node = self.data.get(pos)?.clone();
+        node.set_idx(0);
+        node.set_route("");
+        Some(node)
+    }
+    #[allow(dead_code)]
+    /// clone a subtree rooted at the node of specified position
+    pub fn subtree(&self, pos:usize) -> ETree {
+        self.tree_from_nodes(self.extract_nodes(pos))
+    }
+    fn tree_from_nodes(&self, data:Vec<ETreeNode>) -> ETree {
         let mut tree = ETree {
             indent:self.indent.clone(),
-            count:0,
-            version: self.version.clone(),
-            encoding: self.encoding.clone(),
-            standalone: self.standalone.clone(),
-            data: Vec::new(),
-            crlf: self.crlf.clone(),
-            enable_index: false,
-            index: HashMap::new(),
+            count:data.iter().map(|x| x.get_idx() + 1).max().unwrap_or(0),
+            version:self.version.clone(),
+            declared:self.declared,
+            decl_tail:self.decl_tail.clone(),
+            encoding:self.encoding.clone(),
+            standalone:self.standalone.clone(),
+            data,
+            crlf:self.crlf.clone(),
+            enable_index:self.enable_index,
+            resolve_entities:self.resolve_entities,
+            entities:self.entities.clone(),
+            namespaces:self.namespaces.clone(),
+            ..ETree::empty()
         };
-        let offspring = self.descendant(pos);
-        let mut node = self.data[pos].clone();
-        let base_root_len = node.get_route().len() - 1;
-        node.set_route(node.get_route().get(base_root_len..).unwrap());
-        tree.data.push(node);
-        for i in offspring {
-            node = self.data[i].clone();
-            node.set_route(node.get_route().get(base_root_len..).unwrap());
-            tree.data.push(node);
-        }
+        tree.generate_index();
         tree
     }
     #[allow(dead_code)]
+    /// clone nodes of a subtree rooted at the node of specified position, routes are rebased to start at `#`
+    pub fn extract_nodes(&self, pos:usize) -> Vec<ETreeNode> {
+        let mut out:Vec<ETreeNode> = Vec::new();
+        if pos < self.data.len() {
+            let base_root_len = self.data[pos].get_route().len() - 1;
+            out.push(self.data[pos].clone());
+            for i in self.descendant(pos) {
+                out.push(self.data[i].clone());
+            }
+            for node in out.iter_mut() {
+                let route = node.get_route().get(base_root_len..).unwrap().to_string();
+                node.set_route(&route);
+            }
+        }
+        out
+    }
+    #[allow(dead_code)]
     /// append sibling node before the node of specified position and return the position of sibling node
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
@@ -282,6 +1057,7 @@ impl ETree {
             self.data.insert(cell.get_idx(), node);
             self.index.insert(self.count, cell.get_idx());
             self.update_index(cell.get_idx() + 1);
+            self.link_inserted(cell.get_idx(), cell.get_idx() + 1);
             self.count += 1;
             Some(cell.get_idx())
         } else {
@@ -300,6 +1076,7 @@ impl ETree {
             self.data.insert(cell.get_idx(), node);
             self.index.insert(self.count, cell.get_idx());
             self.update_index(cell.get_idx() + 1);
+            self.link_inserted(cell.get_idx(), cell.get_idx() + 1);
             self.count += 1;
             Some(cell.get_idx())
         } else {
@@ -307,6 +1084,32 @@ impl ETree {
         }
     }
     #[allow(dead_code)]
+    /// check whether the subtree rooted at `subtree_root` of this tree can be appended as child of `parent`,
+    /// e.g. before moving it with `subtree`, `remove_subtree` and `append_child_tree`
+    ///
+    /// `subtree_root` should be a valid position, `parent` should be an element which is not in the subtree,
+    /// comment, PI, CDATA and DOCTYPE nodes can't have children
+    pub fn can_append_child(&self, parent:usize, subtree_root:usize) -> bool {
+        parent < self.data.len() && subtree_root < self.data.len() && !self.data[parent].is_special() && !self.in_subtree(parent, subtree_root)
+    }
+    #[allow(dead_code)]
+    /// check whether the subtree rooted at `subtree_root` of this tree can be appended before `pos`,
+    /// `subtree_root` should be a valid position, `pos` should not be in the subtree and should not be the first top level node
+    pub fn can_append_previous(&self, pos:usize, subtree_root:usize) -> bool {
+        pos < self.data.len() && subtree_root < self.data.len() && (self.previous(pos).is_some() || self.parent(pos).is_some()) && !self.in_subtree(pos, subtree_root)
+    }
+    #[allow(dead_code)]
+    /// check whether the subtree rooted at `subtree_root` of this tree can be appended after `pos`,
+    /// `subtree_root` should be a valid position, `pos` should not be in the subtree
+    pub fn can_append_next(&self, pos:usize, subtree_root:usize) -> bool {
+        pos < self.data.len() && subtree_root < self.data.len() && !self.in_subtree(pos, subtree_root)
+    }
+    /// check whether the node of position `pos` is `subtree_root` or its descendant
+    fn in_subtree(&self, pos:usize, subtree_root:usize) -> bool {
+        let route = self.data[pos].get_route();
+        pos == subtree_root || route.contains(&format!("#{}#", self.data[subtree_root].get_idx()))
+    }
+    #[allow(dead_code)]
     /// append child node below the node of specified position and return the position of child node
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
@@ -318,6 +1121,7 @@ impl ETree {
             self.data.insert(cell.get_idx(), node);
             self.index.insert(self.count, cell.get_idx());
             self.update_index(cell.get_idx() + 1);
+            self.link_inserted(cell.get_idx(), cell.get_idx() + 1);
             self.count += 1;
             Some(cell.get_idx())
         } else {
@@ -325,121 +1129,223 @@ impl ETree {
         }
     }
     #[allow(dead_code)]
-    /// append sibling tree before the node of specified position and return the position of sibling tree
+    /// return the position of the first child node with specified name,
+    /// or append a new node with that name as the last child and return its position
+    ///
+    /// `None` is returned if `parent` is out of range or is a comment, PI, CDATA or DOCTYPE node
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
-    pub fn append_previous_tree(&mut self, pos:usize, mut tree:ETree) -> Option<usize> {
-        if let Some(cell) = self.prepare_append_previous(pos) {
-            let (startidx, endidx) = tree.subtree_reindex(self.count);
-            if startidx == self.count {
-                self.count = endidx;
-            } else {
-                let (_, _) = tree.subtree_reindex(startidx);
-                let (_, endidx) = tree.subtree_reindex(self.count);
-                self.count = endidx;
-            }
-            let tail = cell.get_tail();
-            tree.data[0].set_tail(&tail);
-            for i in 0..tree.data.len() {
-                let route = format!("{}{}", cell.get_route(), tree.data[i].get_route().get(1..).unwrap());
-                tree.data[i].set_route(&route);
-                self.data.insert(cell.get_idx() + i, tree.data[i].clone());
-                self.index.insert(tree.data[i].get_idx(), cell.get_idx() + i);
-            }
-            self.update_index(cell.get_idx() + tree.data.len());
-            if self.indent.len() > 0 {
-                let lines:Vec<&str> = tail.lines().collect();
-                let mut level = lines[lines.len() - 1].len() / self.indent.len();
-                if self.next(cell.get_idx()).is_none() {
-                    level += 1;
-                }
-                self.pretty_tree(cell.get_idx(), level);
-                self.data[cell.get_idx()].set_tail(&tail);
-            }
-            Some(cell.get_idx())
+    pub fn child_or_create(&mut self, parent:usize, tagname:&str) -> Option<usize> {
+        if parent >= self.data.len() || self.data[parent].is_special() {
+            return None;
+        }
+        match self.children_by_name(parent, tagname).first() {
+            Some(&pos) => Some(pos),
+            None => self.append_child_node(parent, ETreeNode::new(tagname)),
+        }
+    }
+    #[allow(dead_code)]
+    /// append comment as the last child of the node of specified position and return the position of comment
+    ///
+    /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
+    pub fn append_child_comment(&mut self, pos:usize, text:&str) -> Option<usize> {
+        self.append_child_node(pos, ETreeNode::comment(text))
+    }
+    #[allow(dead_code)]
+    /// insert child node below the node of specified position at `index` among its children and return the position of child node
+    ///
+    /// `index` larger than the count of children appends the node as the last child
+    ///
+    /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
+    pub fn insert_child_node(&mut self, parent:usize, index:usize, node:ETreeNode) -> Option<usize> {
+        let sibling = self.children_iter(parent).nth(index);
+        match sibling {
+            Some(sibling) => self.append_previous_node(sibling, node),
+            None => self.append_child_node(parent, node),
+        }
+    }
+    #[allow(dead_code)]
+    /// move all children of the node of specified position into a new child node and return the position of the new node
+    ///
+    /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
+    pub fn wrap_children(&mut self, parent:usize, mut wrapper:ETreeNode) -> Option<usize> {
+        let children = self.children(parent);
+        if children.is_empty() {
+            return self.append_child_node(parent, wrapper);
+        }
+        let route = format!("{}{}#", self.data[parent].get_route(), self.data[parent].get_idx());
+        let level = route.matches('#').count() - 1;
+        let last = children[children.len()-1];
+        let tail = self.data[last].get_tail();
+        wrapper.set_idx(self.count);
+        wrapper.set_route(&route);
+        wrapper.set_text("");
+        wrapper.append_literal(&Slot::Text, self.data[parent].text_ref().unwrap_or(""), &self.data[parent].entity_refs(&Slot::Text));
+        wrapper.set_tail(&tail);
+        self.data[last].set_tail("");
+        if self.indent.is_empty() {
+            self.data[parent].set_text("");
         } else {
-            None
+            let text = format!("{}{}", self.crlf, self.indent_at(level, None));
+            self.data[parent].set_text(&text);
+        }
+        let end = parent + 1 + self.descendant(parent).len();
+        self.replace_route_prefix(parent + 1, end, &route, &format!("{}{}#", route, self.count));
+        self.data.insert(parent + 1, wrapper);
+        self.index.insert(self.count, parent + 1);
+        self.update_index(parent + 2);
+        self.link_inserted(parent + 1, parent + 2);
+        self.link_children(parent + 1);
+        self.count += 1;
+        if !self.indent.is_empty() {
+            self.pretty_tree(parent + 1, level, None);
+            self.data[parent + 1].set_tail(&tail);
+        }
+        Some(parent + 1)
+    }
+    #[allow(dead_code)]
+    /// remove the node of specified position but keep its children in its place, return false if the node has no parent
+    ///
+    /// *Warning*: position which is larger than specified value and obtained before this function all should be re-obtained 
+    pub fn unwrap_node(&mut self, pos:usize) -> bool {
+        let parent = match self.parent(pos) {
+            Some(parent) => parent,
+            None => return false,
+        };
+        if self.data[pos].is_special() {
+            return false;
+        }
+        let route = self.data[pos].get_route();
+        let inner = format!("{}{}#", route, self.data[pos].get_idx());
+        let children = self.children(pos);
+        let mut leading = self.data[pos].get_text().unwrap_or("".to_string());
+        let mut leading_refs = self.data[pos].entity_refs(&Slot::Text);
+        let tail = self.data[pos].get_tail();
+        let tail_refs = self.data[pos].entity_refs(&Slot::Tail);
+        if let Some(&last) = children.last() {
+            self.data[last].append_literal(&Slot::Tail, &tail, &tail_refs);
+        } else {
+            leading_refs.extend(tail_refs.iter().map(|x| leading.len() + x));
+            leading.push_str(&tail);
+        }
+        match self.previous(pos) {
+            Some(previous) => self.data[previous].append_literal(&Slot::Tail, &leading, &leading_refs),
+            None => self.data[parent].append_literal(&Slot::Text, &leading, &leading_refs),
+        }
+        self.unlink_unwrapped(pos);
+        let end = pos + 1 + self.descendant(pos).len();
+        self.replace_route_prefix(pos + 1, end, &inner, &route);
+        self.index.remove(&self.data[pos].get_idx());
+        self.data.remove(pos);
+        self.update_index(pos);
+        if !self.indent.is_empty() {
+            let level = route.matches('#').count() - 2;
+            let parent_tail = self.data[parent].get_tail();
+            self.pretty_tree(parent, level, None);
+            self.data[parent].set_tail(&parent_tail);
+        }
+        true
+    }
+    #[allow(dead_code)]
+    /// append sibling tree before the node of specified position and return the position of sibling tree
+    ///
+    /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
+    pub fn append_previous_tree(&mut self, pos:usize, tree:ETree) -> Option<usize> {
+        if tree.data.is_empty() {
+            return None;
         }
+        let cell = self.prepare_append_previous(pos)?;
+        Some(self.splice_tree(cell, tree))
     }
     #[allow(dead_code)]
     /// append sibling tree after the node of specified position and return the position of sibling tree
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
-    pub fn append_next_tree(&mut self, pos:usize, mut tree:ETree) -> Option<usize> {
-        if let Some(cell) = self.prepare_append_next(pos) {
-            let (startidx, endidx) = tree.subtree_reindex(self.count);
-            if startidx == self.count {
-                self.count = endidx;
-            } else {
-                let (_, _) = tree.subtree_reindex(startidx);
-                let (_, endidx) = tree.subtree_reindex(self.count);
-                self.count = endidx;
-            }
-            let tail = cell.get_tail();
-            tree.data[0].set_tail(&tail);
-            for i in 0..tree.data.len() {
-                let route = format!("{}{}", cell.get_route(), tree.data[i].get_route().get(1..).unwrap());
-                tree.data[i].set_route(&route);
-                self.data.insert(cell.get_idx() + i, tree.data[i].clone());
-                self.index.insert(tree.data[i].get_idx(), cell.get_idx() + i);
-            }
-            self.update_index(cell.get_idx() + tree.data.len());
-            if self.indent.len() > 0 {
-                let lines:Vec<&str> = tail.lines().collect();
-                let mut level = lines[lines.len() - 1].len() / self.indent.len();
-                if self.next(cell.get_idx()).is_none() {
-                    level += 1;
-                }
-                self.pretty_tree(cell.get_idx(), level);
-                self.data[cell.get_idx()].set_tail(&tail);
-            }
-            Some(cell.get_idx())
-        } else {
-            None
+    pub fn append_next_tree(&mut self, pos:usize, tree:ETree) -> Option<usize> {
+        if tree.data.is_empty() {
+            return None;
         }
+        let cell = self.prepare_append_next(pos)?;
+        Some(self.splice_tree(cell, tree))
     }
     #[allow(dead_code)]
     /// append child tree below the node of specified position and return the position of child tree
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
-    pub fn append_child_tree(&mut self, pos:usize, mut tree:ETree) -> Option<usize> {
-        if let Some(cell) = self.prepare_append_child(pos) {
-            let (startidx, endidx) = tree.subtree_reindex(self.count);
-            if startidx == self.count {
-                self.count = endidx;
-            } else {
-                let (_, _) = tree.subtree_reindex(startidx);
-                let (_, endidx) = tree.subtree_reindex(self.count);
-                self.count = endidx;
-            }
-            let tail = cell.get_tail();
-            tree.data[0].set_tail(&tail);
-            for i in 0..tree.data.len() {
-                let route = format!("{}{}", cell.get_route(), tree.data[i].get_route().get(1..).unwrap());
-                tree.data[i].set_route(&route);
-                self.data.insert(cell.get_idx() + i, tree.data[i].clone());
-                self.index.insert(tree.data[i].get_idx(), cell.get_idx() + i);
-            }
-            self.update_index(cell.get_idx() + tree.data.len());
-            if self.indent.len() > 0 {
-                let lines:Vec<&str> = tail.lines().collect();
-                let mut level = lines[lines.len() - 1].len() / self.indent.len();
-                if self.next(cell.get_idx()).is_none() {
-                    level += 1;
-                }
-                self.pretty_tree(cell.get_idx(), level);
-                self.data[cell.get_idx()].set_tail(&tail);
-            }
-            Some(cell.get_idx())
-        } else {
-            None
+    pub fn append_child_tree(&mut self, pos:usize, tree:ETree) -> Option<usize> {
+        if tree.data.is_empty() {
+            return None;
         }
+        let cell = self.prepare_append_child(pos)?;
+        Some(self.splice_tree(cell, tree))
+    }
+    #[allow(dead_code)]
+    /// parse `xml` as a fragment and append it as child tree below the node of specified position, return the position of child tree
+    ///
+    /// `None` is returned if `xml` is not a valid fragment (see `parse_fragment`)
+    ///
+    /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
+    pub fn append_child_xml(&mut self, pos:usize, xml:&str) -> Option<usize> {
+        let tree = ETree::parse_fragment(xml).ok()?;
+        self.append_child_tree(pos, tree)
+    }
+    #[allow(dead_code)]
+    /// append sibling node before the node of specified position and return its stable id
+    pub fn append_previous_node_id(&mut self, pos:usize, node:ETreeNode) -> Option<NodeId> {
+        let pos = self.append_previous_node(pos, node)?;
+        self.id(pos)
+    }
+    #[allow(dead_code)]
+    /// append sibling node after the node of specified position and return its stable id
+    pub fn append_next_node_id(&mut self, pos:usize, node:ETreeNode) -> Option<NodeId> {
+        let pos = self.append_next_node(pos, node)?;
+        self.id(pos)
+    }
+    #[allow(dead_code)]
+    /// append child node below the node of specified position and return its stable id
+    pub fn append_child_node_id(&mut self, pos:usize, node:ETreeNode) -> Option<NodeId> {
+        let pos = self.append_child_node(pos, node)?;
+        self.id(pos)
+    }
+    #[allow(dead_code)]
+    /// append sibling tree before the node of specified position and return its stable id
+    pub fn append_previous_tree_id(&mut self, pos:usize, tree:ETree) -> Option<NodeId> {
+        let pos = self.append_previous_tree(pos, tree)?;
+        self.id(pos)
+    }
+    #[allow(dead_code)]
+    /// append sibling tree after the node of specified position and return its stable id
+    pub fn append_next_tree_id(&mut self, pos:usize, tree:ETree) -> Option<NodeId> {
+        let pos = self.append_next_tree(pos, tree)?;
+        self.id(pos)
+    }
+    #[allow(dead_code)]
+    /// append child tree below the node of specified position and return its stable id
+    pub fn append_child_tree_id(&mut self, pos:usize, tree:ETree) -> Option<NodeId> {
+        let pos = self.append_child_tree(pos, tree)?;
+        self.id(pos)
+    }
+    #[allow(dead_code)]
+    /// insert child node below the node of specified position at `index` among its children and return its stable id
+    pub fn insert_child_node_id(&mut self, parent:usize, index:usize, node:ETreeNode) -> Option<NodeId> {
+        let pos = self.insert_child_node(parent, index, node)?;
+        self.id(pos)
     }
     #[allow(dead_code)]
     /// remove a subtree rooted at the node of specified position
     ///
     /// *Warning*: position which is larger than specified value and obtained before this function all should be re-obtained 
     pub fn remove(&mut self, pos:usize) {
+        self.remove_subtree(pos);
+    }
+    #[allow(dead_code)]
+    /// remove a subtree rooted at the node of specified position and return it as a standalone tree
+    ///
+    /// *Warning*: position which is larger than specified value and obtained before this function all should be re-obtained 
+    pub fn remove_subtree(&mut self, pos:usize) -> Option<ETree> {
+        if pos >= self.data.len() {
+            return None;
+        }
         if let Some(previous) = self.previous(pos) {
             let tail = self.data[pos].get_tail();
             self.data[previous].set_tail(&tail);
@@ -452,207 +1358,490 @@ impl ETree {
                 self.data[parent].set_text(&text);
             }
         }
-        let offspring = self.descendant(pos);
-        let mut i = offspring.len();
-        while i > 0 {
-            i -= 1;
-            self.index.remove(&self.data[offspring[i]].get_idx());
-            self.data.remove(offspring[i]);
+        let end = pos + 1 + self.descendant(pos).len();
+        let base_root_len = self.data[pos].get_route().len() - 1;
+        self.unlink_removed(pos, end);
+        let mut nodes:Vec<ETreeNode> = self.data.drain(pos..end).collect();
+        for node in nodes.iter_mut() {
+            self.index.remove(&node.get_idx());
+            let route = node.get_route().get(base_root_len..).unwrap().to_string();
+            node.set_route(&route);
         }
-        self.index.remove(&self.data[pos].get_idx());
-        self.data.remove(pos);
         self.update_index(pos);
+        Some(self.tree_from_nodes(nodes))
+    }
+    #[allow(dead_code)]
+    /// renumber idx of all nodes to their positions and rewrite routes, e.g. after heavy editing
+    ///
+    /// *Warning*: idx and `NodeId` obtained before this function all should be re-obtained
+    pub fn compact(&mut self) {
+        let remap:HashMap<String, String> = self.data.iter().enumerate().map(|(i, x)| {
+            (x.get_idx().to_string(), i.to_string())
+        }).collect();
+        for (i, node) in self.data.iter_mut().enumerate() {
+            let route = node.get_route();
+            let route:Vec<&str> = route.split('#').map(|x| remap.get(x).map(|y| y.as_str()).unwrap_or(x)).collect();
+            node.set_route(&route.join("#"));
+            node.set_idx(i);
+        }
+        self.count = self.data.len();
+        self.generate_index();
+    }
+    #[allow(dead_code)]
+    /// replace `from` with `to` in text and tail of all nodes, and also in attr values if `include_attrs` is true,
+    /// return the number of changed nodes
+    ///
+    /// text of CDATA nodes is replaced, text of comment, PI and DOCTYPE nodes is kept
+    pub fn replace_text_all(&mut self, from:&str, to:&str, include_attrs:bool) -> usize {
+        if from.is_empty() {
+            return 0;
+        }
+        let mut changed = 0;
+        for node in self.data.iter_mut() {
+            let mut hit = false;
+            let has_text = !node.is_special() || node.get_localname() == "<CData>";
+            // entity references are kept, only literal text is replaced
+            if has_text && matches!(node.text_ref(), Some(x) if x.contains(from)) {
+                node.map_literal(&Slot::Text, |x, _, _| x.replace(from, to));
+                hit = true;
+            }
+            if node.tail_ref().contains(from) {
+                node.map_literal(&Slot::Tail, |x, _, _| x.replace(from, to));
+                hit = true;
+            }
+            if include_attrs {
+                let keys:Vec<String> = node.get_attr_iter().filter(|x| x.1.contains(from)).map(|x| x.0.clone()).collect();
+                for key in keys.iter() {
+                    node.map_literal(&Slot::Attr(key.clone()), |x, _, _| x.replace(from, to));
+                }
+                hit |= !keys.is_empty();
+            }
+            if hit {
+                changed += 1;
+            }
+        }
+        changed
+    }
+    #[allow(dead_code)]
+    /// make elements without children and with whitespace-only text empty, so that they are written as `<a/>`
+    ///
+    /// elements with comment, PI or CDATA children and elements in `xml:space="preserve"` are kept
+    pub fn collapse_empty(&mut self) {
+        let inside = self.inside_preserved_space();
+        for (pos, &skip) in inside.iter().enumerate() {
+            if skip || self.data[pos].is_special() || self.space_preserved(pos) || !self.children(pos).is_empty() {
+                continue;
+            }
+            if matches!(self.data[pos].text_ref(), Some(x) if x.trim().is_empty()) {
+                self.data[pos].take_text();
+            }
+        }
+    }
+    #[allow(dead_code)]
+    /// replace contents of the node of specified position with text, all children are removed
+    ///
+    /// *Warning*: position which is larger than pos and obtained before this function all should be re-obtained 
+    pub fn set_element_text(&mut self, pos:usize, text:&str) {
+        if pos >= self.data.len() {
+            return;
+        }
+        let mut children = self.children(pos);
+        while let Some(child) = children.pop() {
+            self.remove(child);
+        }
+        self.data[pos].set_text(text);
+    }
+    #[allow(dead_code)]
+    /// move the text of the node of specified position into a CDATA section and return the position of the CDATA node
+    ///
+    /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained 
+    pub fn wrap_text_as_cdata(&mut self, pos:usize) -> Option<usize> {
+        if pos >= self.data.len() || self.data[pos].is_special() {
+            return None;
+        }
+        let node = &self.data[pos];
+        let text = node.get_text().filter(|x| !x.is_empty())?;
+        let mut cdata = ETreeNode::new("<CData>");
+        cdata.set_idx(self.count);
+        cdata.set_text(&text);
+        cdata.set_route(&format!("{}{}#", node.get_route(), node.get_idx()));
+        self.data[pos].set_text("");
+        self.data.insert(pos + 1, cdata);
+        self.index.insert(self.count, pos + 1);
+        self.update_index(pos + 2);
+        self.link_inserted(pos + 1, pos + 2);
+        self.count += 1;
+        Some(pos + 1)
     }
     #[allow(dead_code)]
     /// clear indent and return old indent
+    ///
+    /// whitespace inside elements with `xml:space="preserve"` is kept
     pub fn noindent(&mut self) -> String {
         let oldindent = format!("{}{}", self.crlf, self.indent);
         self.indent = "".to_string();
+        self.decl_tail = None;
         self.crlf = "".to_string();
-        for item in self.data.iter_mut() {
-            item.set_tail(item.get_tail().trim());
-            if let Some(text) = item.get_text() {
-                item.set_text(text.trim());
+        let inside = self.inside_preserved_space();
+        for (pos, &skip) in inside.iter().enumerate() {
+            if skip {
+                continue;
             }
+            self.data[pos].map_literal(&Slot::Tail, trim_part);
+            if self.space_preserved(pos) {
+                continue;
+            }
+            self.data[pos].map_literal(&Slot::Text, trim_part);
         }
         oldindent
     }
     #[allow(dead_code)]
     /// format nodes according to indent
+    ///
+    /// whitespace inside elements with `xml:space="preserve"` is kept
     pub fn pretty(&mut self, indent:&str) {
+        self.pretty_from(indent, 0);
+    }
+    #[allow(dead_code)]
+    /// format nodes according to indent as if the root node is at depth `base_level`
+    ///
+    /// useful for a fragment which will be embedded into a larger document
+    pub fn pretty_from(&mut self, indent:&str, base_level:usize) {
+        self.set_indent(indent);
+        self.normalize_from(base_level, None);
+    }
+    #[allow(dead_code)]
+    /// format nodes according to indent, nodes deeper than `max_depth` are indented as the nodes at `max_depth`
+    ///
+    /// the cap is not kept, later edits and `normalize_whitespace` indent nodes by their full depth,
+    /// use `WriteOptions::indent_cap` to apply it on write
+    pub fn pretty_capped(&mut self, indent:&str, max_depth:usize) {
         self.set_indent(indent);
+        self.normalize_from(0, Some(max_depth));
+    }
+    #[allow(dead_code)]
+    /// format nodes again according to current indent and line ending, e.g. after structural edits
+    pub fn normalize_whitespace(&mut self) {
+        self.normalize_from(0, None);
+    }
+    fn normalize_from(&mut self, level:usize, max_depth:Option<usize>) {
+        self.decl_tail = None;
         let nodecnt = self.data.len();
         let mut idx = 0;
         while idx < nodecnt {
-            if self.data[idx].get_localname().starts_with("<") && self.data[idx].get_localname().ends_with(">") {
+            if self.data[idx].is_special() {
                 self.data[idx].set_tail(&self.crlf);
             } else {
                 break;
             }
             idx += 1;
         }
-        self.pretty_tree(idx, 0);
+        if idx < nodecnt {
+            self.pretty_tree(idx, level, max_depth);
+        }
     }
 
-    fn read(&mut self, data:&str) {
-        let mut reader = Reader::from_str(data);
-        let mut buf = Vec::new();
-        let mut ns_buf = Vec::new();
+    fn read(&mut self, data:&str, opts:&ReadOptions) -> Result<(), ETreeError> {
+        let (max_depth, max_nodes) = (opts.max_depth, opts.max_nodes);
         let mut status = 0;
         let mut route = "#".to_string();
-        let close_tag = Regex::new(r"^(?P<parent>#.*?)(?P<current>\d+)#$").unwrap();
         let mut closeidx = 0;
-        loop {
-            match reader.read_namespaced_event(&mut buf, &mut ns_buf) {
-                Ok((ref ns, Event::Start(ref e))) => {
-                    status = 1;
-                    let fulltag = String::from_utf8(e.name().to_vec()).unwrap();
-                    let shorttag = String::from_utf8(e.local_name().to_vec()).unwrap();
-                    let prefixlen = fulltag.len() - shorttag.len();
-                    let prefix = if prefixlen > 0 {
-                        fulltag.get(..prefixlen-1).unwrap().to_string()
-                    } else {
-                        "".to_string()
-                    };
-                    let mut node = ETreeNode::new(&shorttag);
-                    node.set_idx(self.count);
-                    if ns.is_some() {
-                        node.set_namespace(&String::from_utf8(ns.unwrap().to_vec()).unwrap());
-                    }
-                    node.set_namespace_abbrev(&prefix);
-                    node.set_text("");
-                    node.set_route(&route);
-                    for item in e.attributes() {
-                        if let Ok(attr) = item {
-                            node.set_attr(&String::from_utf8(attr.key.to_vec()).unwrap(), &attr.unescape_and_decode_value(&reader).unwrap());
-                        }
-                    }
-                    self.data.push(node);
-                    route = format!("{}{}#", route, self.count);
-                    self.count += 1;
-                },
-                Ok((_, Event::End(_))) => {
-                    status = 2;
-                    if let Some(c) = close_tag.captures(route.clone().as_str()) {
-                        route = c.name("parent").unwrap().as_str().to_string();
-                        let current = c.name("current").unwrap().as_str();
-                        closeidx = current.parse().unwrap();
-                    }
-                },
-                Ok((ref ns, Event::Empty(ref e))) => {
+        self.parse_events(data, opts.trim_text, |tree, position, event, refs| {
+            let open = matches!(event, NodeEvent::Start(_));
+            let mut node = match event {
+                NodeEvent::Start(node) | NodeEvent::Empty(node) => node,
+                NodeEvent::End(_) => {
                     status = 2;
-                    let fulltag = String::from_utf8(e.name().to_vec()).unwrap();
-                    let shorttag = String::from_utf8(e.local_name().to_vec()).unwrap();
-                    let prefixlen = fulltag.len() - shorttag.len();
-                    let prefix = if prefixlen > 0 {
-                        fulltag.get(..prefixlen-1).unwrap().to_string()
-                    } else {
-                        "".to_string()
-                    };
-                    let mut node = ETreeNode::new(&shorttag);
-                    node.set_idx(self.count);
-                    if ns.is_some() {
-                        node.set_namespace(&String::from_utf8(ns.unwrap().to_vec()).unwrap());
-                    }
-                    node.set_namespace_abbrev(&prefix);
-                    node.set_route(&route);
-                    for item in e.attributes() {
-                        if let Ok(attr) = item {
-                            node.set_attr(&String::from_utf8(attr.key.to_vec()).unwrap(), &attr.unescape_and_decode_value(&reader).unwrap());
-                        }
+                    if let Some((parent, current)) = split_route(&route) {
+                        route = parent;
+                        closeidx = current;
                     }
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                    return Ok(());
                 },
-                Ok((_, Event::Text(e))) => {
+                NodeEvent::Text(text) => {
                     if status == 1 {
-                        if let Some(node) = self.data.get_mut(self.count - 1) {
-                            node.set_text(&e.unescape_and_decode(&reader).unwrap());
+                        if let Some(node) = tree.data.get_mut(tree.count - 1) {
+                            node.set_text("");
+                            node.append_literal(&Slot::Text, &text, refs);
                         }
                     } else if status == 2 {
-                        if let Some(node) = self.data.get_mut(closeidx) {
-                            node.set_tail(&e.unescape_and_decode(&reader).unwrap());
+                        if let Some(node) = tree.data.get_mut(closeidx) {
+                            node.set_tail("");
+                            node.append_literal(&Slot::Tail, &text, refs);
                         }
+                    } else if let Some(tail) = tree.decl_tail.as_mut() {
+                        *tail = text;
                     }
+                    return Ok(());
                 },
-                Ok((_, Event::Comment(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<Comment>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                NodeEvent::Comment(text) => ETreeNode::comment(&text),
+                NodeEvent::CData(text) => ETree::pseudo_node("<CData>", &text),
+                NodeEvent::PI(text) => ETree::pseudo_node("<PI>", &text),
+                NodeEvent::DocType(text) => ETree::pseudo_node("<DocType>", &text),
+            };
+            if route.matches('#').count() > max_depth {
+                return Err(ETreeError::TooDeep { position, max_depth });
+            }
+            if tree.count >= max_nodes {
+                return Err(ETreeError::TooManyNodes { position, max_nodes });
+            }
+            node.set_idx(tree.count);
+            node.set_route(&route);
+            if open {
+                status = 1;
+                route = format!("{}{}#", route, tree.count);
+            } else {
+                status = 2;
+                closeidx = tree.count;
+            }
+            tree.data.push(node);
+            tree.count += 1;
+            Ok(())
+        })
+    }
+    #[allow(dead_code)]
+    /// parse `content` and pass each node event to `handler` without building a tree
+    pub fn read_events<F:FnMut(NodeEvent)>(content:&str, mut handler:F) -> Result<(), ETreeError> {
+        let mut scratch = ETree::empty();
+        scratch.parse_events(content, false, |_, _, event, _| {
+            handler(event);
+            Ok(())
+        })
+    }
+    fn pseudo_node(localname:&str, text:&str) -> ETreeNode {
+        let mut node = ETreeNode::new(localname);
+        node.set_text(text);
+        node
+    }
+    fn element_node(&self, reader:&Reader<&[u8]>, ns:Option<&[u8]>, e:&BytesStart) -> Result<ETreeNode, ETreeError> {
+        let fulltag = String::from_utf8(e.name().to_vec()).unwrap();
+        let shorttag = String::from_utf8(e.local_name().to_vec()).unwrap();
+        let prefixlen = fulltag.len() - shorttag.len();
+        let prefix = if prefixlen > 0 {
+            fulltag.get(..prefixlen-1).unwrap().to_string()
+        } else {
+            "".to_string()
+        };
+        let mut node = ETreeNode::new(&shorttag);
+        if let Some(ns) = ns {
+            node.set_namespace(std::str::from_utf8(ns).unwrap());
+        }
+        node.set_namespace_abbrev(&prefix);
+        // attrs keep source order, a duplicated or malformed attr is an error instead of truncating the rest
+        for attr in e.attributes() {
+            let attr = attr.map_err(|e| xml_error(reader, e))?;
+            let key = std::str::from_utf8(attr.key).unwrap();
+            let (value, refs) = self.unescape_text(reader.decode(&attr.value).unwrap()).map_err(|e| xml_error(reader, e))?;
+            node.set_attr(key, "");
+            node.append_literal(&Slot::Attr(key.to_string()), &value, &refs);
+        }
+        Ok(node)
+    }
+    /// read `data` with quick_xml and convert each event into `NodeEvent`, entities declared in DOCTYPE are recorded
+    ///
+    /// `handler` receives the reader position after the event and byte offsets of unresolved entity references
+    /// of `Text`, and stops reading by returning an error
+    fn parse_events<F:FnMut(&mut ETree, usize, NodeEvent, &[usize]) -> Result<(), ETreeError>>(&mut self, data:&str, trim_text:bool, mut handler:F) -> Result<(), ETreeError> {
+        let mut reader = Reader::from_str(data);
+        reader.trim_text(trim_text);
+        let mut buf = Vec::new();
+        let mut ns_buf = Vec::new();
+        let mut opened = Vec::new();
+        let mut refs = Vec::new();
+        loop {
+            refs.clear();
+            // skipped whitespace and the open tag are read together when trim_text is on
+            let start = reader.buffer_position();
+            let start = start + data[start..].find('<').unwrap_or(0);
+            let event = match reader.read_namespaced_event(&mut buf, &mut ns_buf) {
+                Ok((ns, Event::Start(ref e))) => {
+                    opened.push(String::from_utf8_lossy(e.name()).into_owned());
+                    let mut node = self.element_node(&reader, ns, e)?;
+                    node.set_text("");
+                    node.set_source_offset(Some(start));
+                    NodeEvent::Start(node)
                 },
-                Ok((_, Event::CData(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<CData>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                Ok((_, Event::End(ref e))) => {
+                    opened.pop();
+                    NodeEvent::End(String::from_utf8(e.name().to_vec()).unwrap())
+                },
+                Ok((ns, Event::Empty(ref e))) => {
+                    let mut node = self.element_node(&reader, ns, e)?;
+                    node.set_source_offset(Some(start));
+                    NodeEvent::Empty(node)
+                },
+                Ok((_, Event::Text(e))) => {
+                    let (text, text_refs) = self.unescape_text(reader.decode(&e).unwrap()).map_err(|e| xml_error(&reader, e))?;
+                    if text.is_empty() {
+                        continue;
+                    }
+                    refs = text_refs;
+                    NodeEvent::Text(text)
                 },
+                Ok((_, Event::Comment(e))) => NodeEvent::Comment(reader.decode(&e).unwrap().to_string()),
+                // CDATA content is raw text, entities inside it must not be expanded
+                Ok((_, Event::CData(e))) => NodeEvent::CData(reader.decode(&e).unwrap().to_string()),
                 Ok((_, Event::Decl(ref e))) => {
                     self.version = e.version().unwrap().into_owned();
+                    self.declared = true;
+                    self.decl_tail = Some(String::new());
                     if let Some(x) = e.encoding() {
                         self.encoding = Some(x.unwrap().into_owned());
                     }
                     if let Some(x) = e.standalone() {
                         self.standalone = Some(x.unwrap().into_owned());
                     }
+                    continue;
                 },
-                Ok((_, Event::PI(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<PI>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
-                },
+                Ok((_, Event::PI(e))) => NodeEvent::PI(reader.decode(&e).unwrap().to_string()),
                 Ok((_, Event::DocType(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<DocType>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                    let doctype = reader.decode(&e).unwrap();
+                    for c in ENTITY_DECL.captures_iter(doctype) {
+                        let value = c.name("dquote").or_else(|| c.name("squote")).unwrap().as_str();
+                        let value = quick_xml::escape::unescape(value.as_bytes()).map(|x| String::from_utf8_lossy(&x).into_owned()).unwrap_or_else(|_| value.to_string());
+                        self.entities.insert(c.name("name").unwrap().as_str().to_string(), value);
+                    }
+                    NodeEvent::DocType(doctype.to_string())
+                },
+                Ok((_, Event::Eof)) => {
+                    if let Some(name) = opened.pop() {
+                        return Err(ETreeError::UnclosedTag { name, position:reader.buffer_position() });
+                    }
+                    break;
                 },
-                Ok((_, Event::Eof)) => break,
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+                Err(quick_xml::Error::EndEventMismatch { expected, found }) => {
+                    return Err(ETreeError::MismatchedTag { expected, found, position:reader.buffer_position() });
+                },
+                Err(e) => return Err(xml_error(&reader, e)),
+            };
+            handler(self, reader.buffer_position(), event, &refs)?;
+        }
+        Ok(())
+    }
+    /// unescape `raw` and return byte offsets of entity references which are kept unresolved
+    fn unescape_text(&self, raw:&str) -> quick_xml::Result<(String, Vec<usize>)> {
+        let unescape = |text:&str| -> quick_xml::Result<String> {
+            let unescaped = quick_xml::escape::unescape(text.as_bytes()).map_err(quick_xml::Error::EscapeError)?;
+            Ok(String::from_utf8_lossy(&unescaped).into_owned())
+        };
+        let mut out = String::new();
+        let mut refs = Vec::new();
+        let mut remaining = raw;
+        while let Some(start) = remaining.find('&') {
+            let end = match remaining[start..].find(';') {
+                Some(end) => end,
+                None => break,
+            };
+            if let Some(value) = self.entities.get(&remaining[start+1..start+end]) {
+                out.push_str(&unescape(&remaining[..start])?);
+                if self.resolve_entities {
+                    out.push_str(value);
+                } else {
+                    refs.push(out.len());
+                    out.push_str(&remaining[start..start+end+1]);
+                }
+            } else {
+                // predefined or character reference
+                out.push_str(&unescape(&remaining[..start+end+1])?);
+            }
+            remaining = &remaining[start+end+1..];
+        }
+        out.push_str(&unescape(remaining)?);
+        Ok((out, refs))
+    }
+    /// content of open tag with one attr per line if the element exceeds thresholds of `WriteOptions::wrap_attrs`
+    fn wrapped_attrs(&self, pos:usize, name:&str, attrs:&[String], opts:&WriteOptions) -> Option<String> {
+        let (max_attrs, max_width) = opts.attr_wrap?;
+        let node = &self.data[pos];
+        let level = node.get_route().matches('#').count() - 1;
+        let width = self.indent_at(level, opts.indent_cap).len() + name.len() + attrs.iter().map(|x| x.len() + 1).sum::<usize>() + 2;
+        if attrs.is_empty() || (attrs.len() <= max_attrs && width <= max_width) {
+            return None;
+        }
+        let separator = format!("{}{}", self.crlf, self.indent_at(level + 1, opts.indent_cap));
+        Some(format!("{}{}{}", name, separator, attrs.join(&separator)))
+    }
+    /// value of attr `key` on the nearest ancestor-or-self of the node of specified position,
+    /// `added` is the attrs which will be added to each node by `write`
+    fn in_scope(&self, pos:usize, key:&str, added:&[Vec<(String, String)>]) -> Option<String> {
+        let mut current = Some(pos);
+        while let Some(p) = current {
+            if let Some(value) = self.data[p].get_attr(key) {
+                return Some(value);
+            }
+            if let Some(value) = added.get(p).and_then(|x| x.iter().find(|y| y.0 == key)) {
+                return Some(value.1.clone());
+            }
+            current = self.parent(p);
+        }
+        None
+    }
+    /// names used by `write` and namespace declarations missing for nodes with namespace but without prefix
+    /// or for pending declarations of `ETreeNode::set_attr_ns`
+    fn namespace_fixups(&self) -> (Vec<String>, Vec<Vec<(String, String)>>) {
+        let mut names = Vec::new();
+        let mut decls:Vec<Vec<(String, String)>> = Vec::new();
+        for pos in 0..self.data.len() {
+            let name = self.get_full_name(pos).unwrap();
+            let node = &self.data[pos];
+            let mut added = Vec::new();
+            if !node.get_namespace().is_empty() && node.get_namespace_abbrev().is_empty() {
+                let key = match name.split_once(':') {
+                    Some((prefix, _)) => format!("xmlns:{}", prefix),
+                    None => "xmlns".to_string(),
+                };
+                if self.in_scope(pos, &key, &decls).as_deref() != Some(node.get_namespace().as_str()) {
+                    added.push((key, node.get_namespace()));
+                }
+            }
+            for (prefix, uri) in node.get_pending_namespaces() {
+                let key = format!("xmlns:{}", prefix);
+                if !added.iter().any(|x| x.0 == key) && self.in_scope(pos, &key, &decls).as_deref() != Some(uri.as_str()) {
+                    added.push((key, uri.clone()));
+                }
             }
+            names.push(name);
+            decls.push(added);
         }
+        (names, decls)
     }
-    fn write(&self) -> Vec<u8> {
-        let close_tag = Regex::new(r"^(?P<parent>#.*?)(?P<current>\d+)#$").unwrap();
-        let mut idxmap:HashMap<String, usize> = HashMap::new();
+    fn write(&self) -> Result<Vec<u8>, ETreeError> {
+        self.write_with(&WriteOptions::default())
+    }
+    fn write_nodes(&self, opts:&WriteOptions) -> Result<Vec<u8>, ETreeError> {
+        let corrupt = |previous:usize, index:usize| ETreeError::CorruptTree {
+            previous,
+            previous_route:self.data[previous].get_route(),
+            index,
+            route:self.data.get(index).map_or("#".to_string(), |x| x.get_route()),
+        };
+        let mut idxmap:HashMap<usize, usize> = HashMap::new();
         for idx in 0..self.data.len() {
-            idxmap.insert(self.data[idx].get_idx().to_string(), idx);
+            idxmap.insert(self.data[idx].get_idx(), idx);
         }
+        let (names, decls) = self.namespace_fixups();
         let mut writer = Writer::new(Cursor::new(Vec::new()));
-        let elem = BytesDecl::new(self.version.as_slice(),
-                                  self.encoding.as_deref(),
-                                  self.standalone.as_deref());
-        let _ = writer.write_event(Event::Decl(elem));
-        let _ = writer.write(self.crlf.as_bytes());
+        if opts.declaration {
+            let elem = BytesDecl::new(self.version.as_slice(),
+                                      self.encoding.as_deref(),
+                                      self.standalone.as_deref());
+            let _ = writer.write_event(Event::Decl(elem));
+            let _ = writer.write(self.decl_tail.as_deref().unwrap_or(&self.crlf).as_bytes());
+        }
         let nodelen = self.data.len();
+        if nodelen == 0 {
+            return Ok(writer.into_inner().into_inner());
+        }
         for idx in 0..nodelen {
             if idx > 0 {
                 if self.data[idx].get_route() == self.data[idx-1].get_route() {
                     // Sibling node for last node
                     if self.data[idx-1].get_text().is_some() {
-                        if !(self.data[idx-1].get_localname().starts_with("<") && self.data[idx-1].get_localname().ends_with(">")) {
-                            let elem = BytesEnd::owned(Vec::<u8>::from(self.data[idx-1].get_name()));
+                        if !self.data[idx-1].is_special() {
+                            let elem = BytesEnd::owned(Vec::<u8>::from(names[idx-1].as_str()));
                             assert!(writer.write_event(Event::End(elem)).is_ok());
                         }
-                        let elem = BytesText::from_plain_str(self.data[idx-1].get_tail().as_str()).into_owned();
+                        let elem = BytesText::from_escaped_str(escape_text(self.data[idx-1].tail_ref(), &self.data[idx-1].entity_refs(&Slot::Tail)));
                         assert!(writer.write_event(Event::Text(elem)).is_ok());
                     }
                 } else if self.data[idx].get_route().starts_with(&self.data[idx-1].get_route()) {
@@ -660,111 +1849,163 @@ impl ETree {
                 } else if self.data[idx-1].get_route().starts_with(&self.data[idx].get_route()) {
                     // Close tag
                     if self.data[idx-1].get_text().is_some() {
-                        if !(self.data[idx-1].get_localname().starts_with("<") && self.data[idx-1].get_localname().ends_with(">")) {
-                            let elem = BytesEnd::owned(Vec::<u8>::from(self.data[idx-1].get_name()));
+                        if !self.data[idx-1].is_special() {
+                            let elem = BytesEnd::owned(Vec::<u8>::from(names[idx-1].as_str()));
                             assert!(writer.write_event(Event::End(elem)).is_ok());
                         }
-                        let elem = BytesText::from_plain_str(self.data[idx-1].get_tail().as_str()).into_owned();
+                        let elem = BytesText::from_escaped_str(escape_text(self.data[idx-1].tail_ref(), &self.data[idx-1].entity_refs(&Slot::Tail)));
                         assert!(writer.write_event(Event::Text(elem)).is_ok());
                     }
                     let mut route = self.data[idx-1].get_route();
-                    while let Some(c) = close_tag.captures(&route.clone()) {
-                        route = c.name("parent").unwrap().as_str().to_string();
-                        let current = c.name("current").unwrap().as_str().to_string();
-                        let closeidx = idxmap.get(&current).unwrap();
-                        if !(self.data[*closeidx].get_localname().starts_with("<") && self.data[*closeidx].get_localname().ends_with(">")) {
-                            let elem = BytesEnd::owned(Vec::<u8>::from(self.data[*closeidx].get_name()));
+                    while let Some((parent, current)) = split_route(&route) {
+                        route = parent;
+                        let closeidx = idxmap.get(&current).ok_or_else(|| corrupt(idx-1, idx))?;
+                        if !self.data[*closeidx].is_special() {
+                            let elem = BytesEnd::owned(Vec::<u8>::from(names[*closeidx].as_str()));
                             assert!(writer.write_event(Event::End(elem)).is_ok());
                         }
-                        let elem = BytesText::from_plain_str(self.data[*closeidx].get_tail().as_str()).into_owned();
+                        let elem = BytesText::from_escaped_str(escape_text(self.data[*closeidx].tail_ref(), &self.data[*closeidx].entity_refs(&Slot::Tail)));
                         assert!(writer.write_event(Event::Text(elem)).is_ok());
                         if route == self.data[idx].get_route() {
                             break;
                         }
                     }
                 } else {
-                    panic!("Error route: {}[{}] {}[{}]", idx-1, self.data[idx-1].get_route(), idx, self.data[idx].get_route());
+                    return Err(corrupt(idx-1, idx));
                 }
             }
+            // content of comment, CDATA, PI and DOCTYPE is raw text which is never escaped
             if self.data[idx].get_localname() == "<Comment>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
+                let elem = BytesText::from_escaped_str(self.data[idx].text_ref().unwrap());
                 assert!(writer.write_event(Event::Comment(elem)).is_ok());
             } else if self.data[idx].get_localname() == "<CData>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
+                let elem = BytesText::from_escaped_str(self.data[idx].get_text().unwrap());
                 assert!(writer.write_event(Event::CData(elem)).is_ok());
             } else if self.data[idx].get_localname() == "<PI>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
+                let elem = BytesText::from_escaped_str(self.data[idx].text_ref().unwrap());
                 assert!(writer.write_event(Event::PI(elem)).is_ok());
             } else if self.data[idx].get_localname() == "<DocType>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
+                let elem = BytesText::from_escaped_str(self.data[idx].get_text().unwrap());
                 assert!(writer.write_event(Event::DocType(elem)).is_ok());
             } else {
-                let name = self.data[idx].get_name();
-                let mut elem = BytesStart::borrowed(name.as_bytes(), name.len());
-                for attr in self.data[idx].get_attr_iter() {
-                    elem.push_attribute((attr.0.as_str(), attr.1.as_str()));
+                let name = &names[idx];
+                let mut attrs:Vec<&(String, String)> = self.data[idx].get_attr_iter().chain(decls[idx].iter()).collect();
+                if opts.sort_attrs {
+                    attrs.sort_by(|x, y| x.0.cmp(&y.0));
                 }
+                let attrs:Vec<String> = attrs.iter().map(|x| format!("{}={}{}{}", x.0, opts.quote, escape_text(&x.1, &self.data[idx].entity_refs(&Slot::Attr(x.0.clone()))), opts.quote)).collect();
+                let content = match self.wrapped_attrs(idx, name, &attrs, opts) {
+                    Some(content) => content,
+                    None => std::iter::once(name.clone()).chain(attrs).collect::<Vec<_>>().join(" "),
+                };
+                let elem = BytesStart::owned(content.into_bytes(), name.len());
                 if self.data[idx].get_text().is_some() {
                     assert!(writer.write_event(Event::Start(elem)).is_ok());
-                    let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
+                    let elem = BytesText::from_escaped_str(escape_text(self.data[idx].text_ref().unwrap(), &self.data[idx].entity_refs(&Slot::Text)));
                     assert!(writer.write_event(Event::Text(elem)).is_ok());
                 } else {
-                    assert!(writer.write_event(Event::Empty(elem)).is_ok());
-                    let elem = BytesText::from_plain_str(self.data[idx].get_tail().as_str()).into_owned();
+                    if opts.self_close {
+                        assert!(writer.write_event(Event::Empty(elem)).is_ok());
+                    } else {
+                        assert!(writer.write_event(Event::Start(elem)).is_ok());
+                        let elem = BytesEnd::owned(Vec::<u8>::from(name.as_str()));
+                        assert!(writer.write_event(Event::End(elem)).is_ok());
+                    }
+                    let elem = BytesText::from_escaped_str(escape_text(self.data[idx].tail_ref(), &self.data[idx].entity_refs(&Slot::Tail)));
                     assert!(writer.write_event(Event::Text(elem)).is_ok());
                 }
             }
         }
         // Close all remaining tags
         if self.data[nodelen-1].get_text().is_some() {
-            if !(self.data[nodelen-1].get_localname().starts_with("<") && self.data[nodelen-1].get_localname().ends_with(">")) {
-                let elem = BytesEnd::owned(Vec::<u8>::from(self.data[nodelen-1].get_name()));
+            if !self.data[nodelen-1].is_special() {
+                let elem = BytesEnd::owned(Vec::<u8>::from(names[nodelen-1].as_str()));
                 assert!(writer.write_event(Event::End(elem)).is_ok());
             }
-            let elem = BytesText::from_plain_str(self.data[nodelen-1].get_tail().as_str()).into_owned();
+            let elem = BytesText::from_escaped_str(escape_text(self.data[nodelen-1].tail_ref(), &self.data[nodelen-1].entity_refs(&Slot::Tail)));
             assert!(writer.write_event(Event::Text(elem)).is_ok());
         }
         let mut route = self.data[nodelen-1].get_route();
-        while let Some(c) = close_tag.captures(&route.clone()) {
-            route = c.name("parent").unwrap().as_str().to_string();
-            let current = c.name("current").unwrap().as_str().to_string();
-            let closeidx = idxmap.get(&current).unwrap();
-            if !(self.data[*closeidx].get_localname().starts_with("<") && self.data[*closeidx].get_localname().ends_with(">")) {
-                let elem = BytesEnd::owned(Vec::<u8>::from(self.data[*closeidx].get_name()));
+        while let Some((parent, current)) = split_route(&route) {
+            route = parent;
+            let closeidx = idxmap.get(&current).ok_or_else(|| corrupt(nodelen-1, nodelen))?;
+            if !self.data[*closeidx].is_special() {
+                let elem = BytesEnd::owned(Vec::<u8>::from(names[*closeidx].as_str()));
                 assert!(writer.write_event(Event::End(elem)).is_ok());
             }
-            let elem = BytesText::from_plain_str(self.data[*closeidx].get_tail().as_str()).into_owned();
+            let elem = BytesText::from_escaped_str(escape_text(self.data[*closeidx].tail_ref(), &self.data[*closeidx].entity_refs(&Slot::Tail)));
             assert!(writer.write_event(Event::Text(elem)).is_ok());
             if route == "#" {
                 break;
             }
         }
-        writer.into_inner().into_inner()
+        Ok(writer.into_inner().into_inner())
     }
+    /// detect indent from the whitespace before the first child of each node
+    ///
+    /// the last line of that whitespace should be the indent repeated as many times as the depth of the child
     fn detect_indent(&mut self) {
-        let mut idx = self.data.len();
-        while idx > 0 {
-            idx -= 1;
-            if !(self.data[idx].get_localname().starts_with("<") && self.data[idx].get_localname().ends_with(">")) {
-                break;
+        for pos in 1..self.data.len() {
+            let parent = &self.data[pos - 1];
+            if self.data[pos].get_route() != format!("{}{}#", parent.get_route(), parent.get_idx()) {
+                continue;
             }
-        }
-        if let Some(previous) = self.previous(idx) {
-            if self.data[previous].get_tail().starts_with(&self.data[idx].get_tail()) {
-                self.indent = self.data[previous].get_tail().get(self.data[idx].get_tail().len()..).unwrap().to_string();
+            let text = parent.text_ref().unwrap_or("");
+            if !text.trim().is_empty() || !text.contains(['\n', '\r']) {
+                continue;
             }
-        } else if let Some(parent) = self.parent(idx) {
-            let text = String::from(self.data[parent].get_text().as_deref().unwrap());
-            if text.starts_with(&self.data[idx].get_tail()) {
-                self.indent = text.get(self.data[idx].get_tail().len()..).unwrap().to_string();
+            let line = text.rsplit(['\n', '\r']).next().unwrap();
+            let depth = self.data[pos].get_route().matches('#').count() - 1;
+            if !line.is_empty() && line.len() % depth == 0 {
+                let unit = &line[..line.len() / depth];
+                if unit.repeat(depth) == line {
+                    self.indent = unit.to_string();
+                    return;
+                }
             }
         }
     }
-    fn prepare_append_previous(&mut self, pos:usize) -> Option<ETreeNode> {
-        if pos >= self.data.len() {
-            None
+    /// insert nodes of `tree` at the place prepared by `prepare_append_*` and return the position of the first node
+    ///
+    /// every top level node of `tree` is inserted, the last one takes the tail of the place
+    fn splice_tree(&mut self, cell:ETreeNode, mut tree:ETree) -> usize {
+        let (startidx, endidx) = tree.subtree_reindex(self.count);
+        if startidx == self.count {
+            self.count = endidx;
         } else {
-            if let Some(prev) = self.previous(pos) {
+            let (_, _) = tree.subtree_reindex(startidx);
+            let (_, endidx) = tree.subtree_reindex(self.count);
+            self.count = endidx;
+        }
+        let tops:Vec<usize> = (0..tree.data.len()).filter(|&i| tree.data[i].get_route() == "#").collect();
+        let last = *tops.last().unwrap();
+        let tail = cell.get_tail();
+        tree.data[last].set_tail(&tail);
+        for i in 0..tree.data.len() {
+            let route = format!("{}{}", cell.get_route(), tree.data[i].get_route().get(1..).unwrap());
+            tree.data[i].set_route(&route);
+            self.data.insert(cell.get_idx() + i, tree.data[i].clone());
+            self.index.insert(tree.data[i].get_idx(), cell.get_idx() + i);
+        }
+        self.update_index(cell.get_idx() + tree.data.len());
+        self.link_inserted(cell.get_idx(), cell.get_idx() + tree.data.len());
+        if !self.indent.is_empty() {
+            let mut level = tail.rsplit('\n').next().unwrap_or("").len() / self.indent.len();
+            if self.next(cell.get_idx() + last).is_none() {
+                level += 1;
+            }
+            for i in tops {
+                self.pretty_tree(cell.get_idx() + i, level, None);
+            }
+            self.data[cell.get_idx() + last].set_tail(&tail);
+        }
+        cell.get_idx()
+    }
+    fn prepare_append_previous(&mut self, pos:usize) -> Option<ETreeNode> {
+        if pos >= self.data.len() {
+            None
+        } else {
+            if let Some(prev) = self.previous(pos) {
                 self.prepare_append_next(prev)
             } else if let Some(parent) = self.parent(pos) {
                 let mut node = ETreeNode::new("");
@@ -793,7 +2034,7 @@ impl ETree {
                 self.data[pos].set_tail(&tail);
             }
             let offspring = self.descendant(pos);
-            let newpos = if offspring.len() == 0 {
+            let newpos = if offspring.is_empty() {
                 pos + 1
             } else {
                 offspring[offspring.len()-1] + 1
@@ -812,22 +2053,16 @@ impl ETree {
         match children.len() {
             0 => {
                 // No child exists
-                let previous = self.previous(pos);
-                let tail = if previous.is_some() {
-                    format!("{}", self.data[previous.unwrap()].get_tail())
+                let tail = if let Some(previous) = self.previous(pos) {
+                    self.data[previous].get_tail()
+                } else if let Some(parent) = self.parent(pos) {
+                    self.data[parent].get_text().unwrap_or("".to_string())
                 } else {
-                    let parent = self.parent(pos);
-                    if parent.is_some() {
-                        format!("{}", self.data[parent.unwrap()].get_text().unwrap_or("".to_string()))
-                    } else {
-                        self.crlf.clone()
-                    }
+                    self.crlf.clone()
                 };
                 let text = format!("{}{}", tail, self.indent);
                 node.set_tail(&tail);
-                if self.data[pos].get_text().is_none() {
-                    self.data[pos].set_text(&text);
-                } else if self.data[pos].get_text().as_deref() == Some("") {
+                if self.data[pos].get_text().is_none() || self.data[pos].get_text().as_deref() == Some("") {
                     self.data[pos].set_text(&text);
                 }
                 node.set_idx(pos + 1);
@@ -852,30 +2087,19 @@ impl ETree {
     fn subtree_reindex(&mut self, start_idx:usize) -> (usize, usize) {
         let datacnt = self.data.len();
         if datacnt > 0 {
-            let mut idx_min = self.data[0].get_idx();
-            let mut idx_max = self.data[0].get_idx();
-            let mut idx_cnt = 1;
-            for i in 1..datacnt {
-                if self.data[i].get_idx() > idx_max {
-                    idx_max = self.data[i].get_idx();
-                }
-                if self.data[i].get_idx() < idx_min {
-                    idx_min = self.data[i].get_idx();
-                }
-                idx_cnt += 1;
-            }
-            if (start_idx + idx_cnt <= idx_min) || (start_idx > idx_max) {
-                let mut idx_cur = start_idx;
-                for i in 0..datacnt {
-                    let idx_old = self.data[i].get_idx();
-                    self.data[i].set_idx(idx_cur);
-                    for j in 0..datacnt {
-                        let route = self.data[j].get_route().replace(format!("#{}#", idx_old).as_str(), format!("#{}#", idx_cur).as_str());
-                        self.data[j].set_route(&route);
-                    }
-                    idx_cur += 1;
+            let idx_min = self.data.iter().map(|x| x.get_idx()).min().unwrap();
+            let idx_max = self.data.iter().map(|x| x.get_idx()).max().unwrap();
+            if (start_idx + datacnt <= idx_min) || (start_idx > idx_max) {
+                let remap:HashMap<String, String> = self.data.iter().enumerate().map(|(i, x)| {
+                    (x.get_idx().to_string(), (start_idx + i).to_string())
+                }).collect();
+                for (i, node) in self.data.iter_mut().enumerate() {
+                    let route = node.get_route();
+                    let route:Vec<&str> = route.split('#').map(|x| remap.get(x).map(|y| y.as_str()).unwrap_or(x)).collect();
+                    node.set_route(&route.join("#"));
+                    node.set_idx(start_idx + i);
                 }
-                (start_idx, idx_cur)
+                (start_idx, start_idx + datacnt)
             } else {
                 (idx_max + datacnt + 1, idx_max + datacnt * 2 + 1)
             }
@@ -885,7 +2109,8 @@ impl ETree {
     }
     fn set_indent(&mut self, indent:&str) {
         let lines:Vec<&str> = indent.lines().collect();
-        if lines.len() >= 2 && lines[lines.len() - 1].len() > 0 {
+        let last = lines.last().copied().unwrap_or("");
+        if lines.len() >= 2 && !last.is_empty() {
             if indent.contains("\r\n") {
                 self.crlf = "\r\n".to_string();
             } else if indent.contains("\n") {
@@ -896,29 +2121,84 @@ impl ETree {
         } else {
             self.crlf = "\n".to_string();
         }
-        self.indent = lines[lines.len() - 1].to_string();
+        self.indent = last.to_string();
+    }
+    /// indent of nodes at depth `level`, limited to `max_depth` levels
+    fn indent_at(&self, level:usize, max_depth:Option<usize>) -> String {
+        self.indent.repeat(max_depth.map_or(level, |x| level.min(x)))
     }
-    fn pretty_tree(&mut self, pos:usize, level:usize) {
-        let tail = format!("{}{}", self.crlf, self.indent.repeat(level));
+    fn pretty_tree(&mut self, pos:usize, level:usize, max_depth:Option<usize>) {
+        let tail = format!("{}{}", self.crlf, self.indent_at(level, max_depth));
         self.data[pos].set_tail(&tail);
+        if self.space_preserved(pos) {
+            return;
+        }
         let children = self.children(pos);
-        if children.len() > 0 {
-            let text = format!("{}{}{}",
-                self.data[pos].get_text().as_deref().unwrap().trim(),
-                self.crlf.as_str(),
-                self.indent.repeat(level+1));
-            self.data[pos].set_text(&text);
+        if !children.is_empty() {
+            let indent = format!("{}{}", self.crlf, self.indent_at(level+1, max_depth));
+            self.data[pos].map_literal(&Slot::Text, |x, first, last| {
+                let part = trim_part(x, first, last);
+                if last { part + &indent } else { part }
+            });
             for subpos in children.iter() {
-                self.pretty_tree(*subpos, level+1);
+                self.pretty_tree(*subpos, level+1, max_depth);
             }
             self.data[children[children.len()-1]].set_tail(&tail);
         } else {
-            if !(self.data[pos].get_localname().starts_with("<") && self.data[pos].get_localname().ends_with(">")) {
-                if let Some(text) = self.data[pos].get_text().as_deref() {
-                    self.data[pos].set_text(&text.trim());
+            if !self.data[pos].is_special() {
+                self.data[pos].map_literal(&Slot::Text, trim_part);
+            }
+        }
+    }
+    /// clear whitespace-only text and tails of elements which are not mixed content, and the indent
+    fn trim_blank_text(&mut self) {
+        let inside = self.inside_preserved_space();
+        for (pos, &skip) in inside.iter().enumerate() {
+            if skip || self.data[pos].is_special() || self.space_preserved(pos) {
+                continue;
+            }
+            let children = self.children(pos);
+            if children.is_empty() {
+                continue;
+            }
+            let blank = self.data[pos].text_ref().unwrap_or("").trim().is_empty()
+                && children.iter().all(|&x| self.data[x].tail_ref().trim().is_empty());
+            if blank {
+                self.data[pos].set_text("");
+                for x in children {
+                    self.data[x].set_tail("");
                 }
             }
         }
+        for node in self.data.iter_mut() {
+            if node.get_route() == "#" && node.tail_ref().trim().is_empty() {
+                node.set_tail("");
+            }
+        }
+        self.indent = "".to_string();
+        self.decl_tail = None;
+    }
+    /// check whether the node of specified position has `xml:space="preserve"`
+    fn space_preserved(&self, pos:usize) -> bool {
+        self.data[pos].get_attr("xml:space").as_deref() == Some("preserve")
+    }
+    /// flag nodes which are descendants of an element with `xml:space="preserve"`
+    fn inside_preserved_space(&self) -> Vec<bool> {
+        let preserved:Vec<String> = (0..self.data.len())
+            .filter(|&x| self.space_preserved(x))
+            .map(|x| self.data[x].get_idx().to_string())
+            .collect();
+        self.data.iter().map(|x| {
+            x.get_route().split('#').any(|idx| preserved.iter().any(|y| y == idx))
+        }).collect()
+    }
+    /// replace the leading `from` of routes with `to` for nodes in range [start, end)
+    fn replace_route_prefix(&mut self, start:usize, end:usize, from:&str, to:&str) {
+        for node in self.data[start..end].iter_mut() {
+            if let Some(rest) = node.get_route().strip_prefix(from) {
+                node.set_route(&format!("{}{}", to, rest));
+            }
+        }
     }
     fn generate_index(&mut self) {
         if self.enable_index {
@@ -927,6 +2207,7 @@ impl ETree {
                 self.index.insert(self.data[i].get_idx(), i);
             }
         }
+        self.generate_links();
     }
     fn update_index(&mut self, pos:usize) {
         if self.enable_index {
@@ -937,33 +2218,274 @@ impl ETree {
             }
         }
     }
+    /// build (parent, previous, next) idx for each node, keyed by idx
+    fn generate_links(&mut self) {
+        self.links = HashMap::new();
+        if self.enable_index {
+            self.links_of_range(0, self.data.len());
+        }
+    }
+    /// build links of nodes `start..end`, which are whole subtrees, among themselves
+    fn links_of_range(&mut self, start:usize, end:usize) {
+        let links = &mut self.links;
+        let mut last_sibling:HashMap<&str, usize> = HashMap::new();
+        for node in self.data[start..end].iter() {
+            let route = node.route_ref();
+            let previous = last_sibling.insert(route, node.get_idx());
+            if let Some(link) = previous.and_then(|x| links.get_mut(&x)) {
+                link.2 = Some(node.get_idx());
+            }
+            links.insert(node.get_idx(), (parent_idx(route), previous, None));
+        }
+    }
+    /// link nodes `start..end` just inserted as sibling subtrees, and link them with the siblings around
+    fn link_inserted(&mut self, start:usize, end:usize) {
+        if !self.enable_index || start >= end {
+            return;
+        }
+        self.links_of_range(start, end);
+        let (data, links) = (&self.data, &mut self.links);
+        let route = data[start].route_ref();
+        let first = data[start].get_idx();
+        let last = data[start..end].iter().rev().find(|x| x.route_ref() == route).unwrap().get_idx();
+        let next = data.get(end).filter(|x| x.route_ref() == route).map(|x| x.get_idx());
+        let previous = match next {
+            Some(next) => links.get(&next).and_then(|x| x.1),
+            None => data[..start].iter().rev()
+                .take_while(|x| x.route_ref().starts_with(route))
+                .find(|x| x.route_ref() == route)
+                .map(|x| x.get_idx()),
+        };
+        if let Some(link) = links.get_mut(&first) {
+            link.1 = previous;
+        }
+        if let Some(link) = links.get_mut(&last) {
+            link.2 = next;
+        }
+        if let Some(link) = previous.and_then(|x| links.get_mut(&x)) {
+            link.2 = Some(first);
+        }
+        if let Some(link) = next.and_then(|x| links.get_mut(&x)) {
+            link.1 = Some(last);
+        }
+    }
+    /// link children of the node of specified position to it as their parent
+    fn link_children(&mut self, pos:usize) {
+        if !self.enable_index {
+            return;
+        }
+        let parent = Some(self.data[pos].get_idx());
+        for child in self.children_iter(pos).map(|x| self.data[x].get_idx()).collect::<Vec<usize>>() {
+            if let Some(link) = self.links.get_mut(&child) {
+                link.0 = parent;
+            }
+        }
+    }
+    /// drop links of nodes `start..end` of a subtree and link its siblings with each other, before removing it
+    fn unlink_removed(&mut self, start:usize, end:usize) {
+        if !self.enable_index || start >= end {
+            return;
+        }
+        if let Some(&(_, previous, next)) = self.links.get(&self.data[start].get_idx()) {
+            if let Some(link) = previous.and_then(|x| self.links.get_mut(&x)) {
+                link.2 = next;
+            }
+            if let Some(link) = next.and_then(|x| self.links.get_mut(&x)) {
+                link.1 = previous;
+            }
+        }
+        for node in self.data[start..end].iter() {
+            self.links.remove(&node.get_idx());
+        }
+    }
+    /// drop links of the node of specified position and put its children in its place, before removing it
+    fn unlink_unwrapped(&mut self, pos:usize) {
+        if !self.enable_index {
+            return;
+        }
+        let (parent, previous, next) = match self.links.remove(&self.data[pos].get_idx()) {
+            Some(link) => link,
+            None => return,
+        };
+        let children:Vec<usize> = self.children_iter(pos).map(|x| self.data[x].get_idx()).collect();
+        for child in children.iter() {
+            if let Some(link) = self.links.get_mut(child) {
+                link.0 = parent;
+            }
+        }
+        if let Some(link) = children.first().and_then(|x| self.links.get_mut(x)) {
+            link.1 = previous;
+        }
+        if let Some(link) = children.last().and_then(|x| self.links.get_mut(x)) {
+            link.2 = next;
+        }
+        if let Some(link) = previous.and_then(|x| self.links.get_mut(&x)) {
+            link.2 = children.first().copied().or(next);
+        }
+        if let Some(link) = next.and_then(|x| self.links.get_mut(&x)) {
+            link.1 = children.last().copied().or(previous);
+        }
+    }
+    #[cfg(feature = "json")]
+    #[allow(dead_code)]
+    /// convert elements into JSON, comment, PI, CDATA and DOCTYPE nodes are skipped
+    ///
+    /// - element is converted into an object, and the root element is wrapped as `{"root": {...}}`
+    /// - attr is stored with key `@attrname`
+    /// - text without surrounding whitespace is stored with key `#text` if not empty, tails are dropped
+    /// - child element is stored with its name as key, children with the same name are stored in an array
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut out = serde_json::Map::new();
+        for pos in 0..self.data.len() {
+            if self.data[pos].get_route() == "#" && !self.data[pos].is_special() {
+                Self::json_insert(&mut out, self.data[pos].get_name(), self.json_element(pos));
+            }
+        }
+        serde_json::Value::Object(out)
+    }
+    #[cfg(feature = "json")]
+    fn json_element(&self, pos:usize) -> serde_json::Value {
+        let mut out = serde_json::Map::new();
+        for (key, value) in self.data[pos].get_attr_iter() {
+            out.insert(format!("@{}", key), serde_json::Value::String(value.clone()));
+        }
+        let text = self.data[pos].text_ref().unwrap_or("").trim();
+        if !text.is_empty() {
+            out.insert("#text".to_string(), serde_json::Value::String(text.to_string()));
+        }
+        for child in self.children_iter(pos) {
+            if !self.data[child].is_special() {
+                Self::json_insert(&mut out, self.data[child].get_name(), self.json_element(child));
+            }
+        }
+        serde_json::Value::Object(out)
+    }
+    #[cfg(feature = "json")]
+    fn json_insert(map:&mut serde_json::Map<String, serde_json::Value>, key:String, value:serde_json::Value) {
+        match map.get_mut(&key) {
+            Some(serde_json::Value::Array(list)) => list.push(value),
+            Some(old) => *old = serde_json::Value::Array(vec![old.take(), value]),
+            None => { map.insert(key, value); },
+        }
+    }
     #[allow(dead_code)]
     /// find the first node that matches `path` from the root node
+    ///
+    /// use `find_all` or `find_iter` to get every matched node
     pub fn find(&self, path:&str) -> Option<usize> {
         self.find_at(path, self.root())
     }
     #[allow(dead_code)]
     /// find the first node that matches `path` from specified node
+    ///
+    /// leading `/` selects children of the specified node, e.g. `/b` from `a` finds `a/b`,
+    /// use `find_absolute` for a path from the document
     pub fn find_at(&self, path:&str, pos:usize) -> Option<usize> {
         let mut iter = self.find_at_iter(path, pos);
         iter.next()
     }
     #[allow(dead_code)]
+    /// find all nodes that matches `path` from the root node
+    pub fn find_all(&self, path:&str) -> Vec<usize> {
+        self.find_iter(path).collect()
+    }
+    #[allow(dead_code)]
+    /// find values of nodes that matches `path` from the root node
+    ///
+    /// attr values for `path/@attrname`, otherwise text of nodes
+    pub fn find_values(&self, path:&str) -> Vec<String> {
+        let iter = self.find_iter(path);
+        match iter.attr_name().map(|x| x.to_string()) {
+            Some(attr) => iter.filter_map(|x| self.data[x].get_attr(&attr)).collect(),
+            None => iter.map(|x| self.data[x].get_text().unwrap_or_default()).collect(),
+        }
+    }
+    #[allow(dead_code)]
+    /// collect (position, key, value) of all attrs of nodes that matches `path` from the root node
+    pub fn collect_attrs(&self, path:&str) -> Vec<(usize, String, String)> {
+        self.find_iter(path).flat_map(|x| {
+            self.data[x].get_attr_iter().map(move |(k, v)| (x, k.clone(), v.clone()))
+        }).collect()
+    }
+    #[allow(dead_code)]
+    /// get distinct names of elements in the tree and their counts, sorted by count descending and then by name
+    pub fn tag_names(&self) -> Vec<(String, usize)> {
+        let mut counts:HashMap<String, usize> = HashMap::new();
+        for node in self.data.iter().filter(|x| !x.is_special()) {
+            *counts.entry(node.get_name()).or_insert(0) += 1;
+        }
+        let mut out:Vec<(String, usize)> = counts.into_iter().collect();
+        out.sort_by(|x, y| y.1.cmp(&x.1).then_with(|| x.0.cmp(&y.0)));
+        out
+    }
+    #[allow(dead_code)]
+    /// count nodes that matches `path` from the root node
+    pub fn count(&self, path:&str) -> usize {
+        self.find_iter(path).count()
+    }
+    #[allow(dead_code)]
+    /// check whether any node matches `path` from the root node, search stops at the first match
+    pub fn exists(&self, path:&str) -> bool {
+        self.find(path).is_some()
+    }
+    #[allow(dead_code)]
+    /// find the `n`-th (0-based) node that matches `path` from the root node
+    pub fn find_nth(&self, path:&str, n:usize) -> Option<usize> {
+        self.find_iter(path).nth(n)
+    }
+    #[allow(dead_code)]
     /// find nodes that matches `path` from the root node
-    pub fn find_iter(&self, path:&str) -> XPathIterator {
+    pub fn find_iter(&self, path:&str) -> XPathIterator<'_> {
         self.find_at_iter(path, self.root())
     }
     #[allow(dead_code)]
+    /// find nodes that matches `path` from the root node, or report the syntax error of `path`
+    ///
+    /// `find` and `find_iter` simply match nothing for a malformed `path`
+    pub fn find_checked(&self, path:&str) -> Result<XPathIterator<'_>, XPathError> {
+        XPathIterator::try_new(self, path, self.root(), true)
+    }
+    #[allow(dead_code)]
+    /// find nodes that matches `path` from the root node, tag names are matched by local name regardless of prefix
+    pub fn find_local_iter(&self, path:&str) -> XPathIterator<'_> {
+        XPathIterator::new(self, path, self.root(), true).with_name_match(NameMatch::LocalName)
+    }
+    #[allow(dead_code)]
+    /// find nodes that matches `path` from the root node, tag names are matched by local name and namespace
+    ///
+    /// prefixes in `path` must be registered by `register_namespace`, tag names without prefix only match nodes without namespace
+    pub fn find_ns_iter(&self, path:&str) -> XPathIterator<'_> {
+        XPathIterator::new(self, path, self.root(), true).with_name_match(NameMatch::Namespace)
+    }
+    #[allow(dead_code)]
     /// find nodes that matches `path` from specified node
-    pub fn find_at_iter(&self, path:&str, pos:usize) -> XPathIterator {
+    pub fn find_at_iter(&self, path:&str, pos:usize) -> XPathIterator<'_> {
         XPathIterator::new(self, path, pos, true)
     }
     #[allow(dead_code)]
+    /// find the first node that matches document-absolute `path`
+    ///
+    /// leading `/` selects top level nodes, so the path starts with the root element, e.g. `/root/a`,
+    /// while `find("/a")` starts with children of the root element
+    pub fn find_absolute(&self, path:&str) -> Option<usize> {
+        self.find_absolute_iter(path).next()
+    }
+    #[allow(dead_code)]
+    /// find nodes that matches document-absolute `path`, see `find_absolute`
+    pub fn find_absolute_iter(&self, path:&str) -> XPathIterator<'_> {
+        XPathIterator::new(self, path, self.root(), true).with_absolute()
+    }
+    #[allow(dead_code)]
     /// find the last node that matches `path` from the root node
     pub fn rfind(&self, path:&str) -> Option<usize> {
         self.rfind_at(path, self.root())
     }
     #[allow(dead_code)]
+    /// find the `n`-th (0-based) node from the end that matches `path` from the root node
+    pub fn rfind_nth(&self, path:&str, n:usize) -> Option<usize> {
+        self.rfind_iter(path).nth(n)
+    }
+    #[allow(dead_code)]
     /// find the last node that matches `path` from specified node
     pub fn rfind_at(&self, path:&str, pos:usize) -> Option<usize> {
         let mut iter = self.rfind_at_iter(path, pos);
@@ -971,78 +2493,311 @@ impl ETree {
     }
     #[allow(dead_code)]
     /// find nodes in reverse order that matches `path` from the root node
-    pub fn rfind_iter(&self, path:&str) -> XPathIterator {
+    pub fn rfind_iter(&self, path:&str) -> XPathIterator<'_> {
         self.rfind_at_iter(path, self.root())
     }
     #[allow(dead_code)]
     /// find nodes in reverse order that matches `path` from specified node
-    pub fn rfind_at_iter(&self, path:&str, pos:usize) -> XPathIterator {
+    pub fn rfind_at_iter(&self, path:&str, pos:usize) -> XPathIterator<'_> {
         XPathIterator::new(self, path, pos, false)
     }
+    #[allow(dead_code)]
+    /// evaluate `expr` from specified node
+    ///
+    /// - `count(path)`: number of matched nodes
+    /// - `string(path)`: string value of the first matched node, or the first attr value for `path/@attrname`
+    /// - `boolean(path)`: whether any node is matched
+    /// - `path/@attrname`: attr values of matched nodes which have the attr
+    /// - `path`: matched nodes
+    pub fn evaluate(&self, expr:&str, context:usize) -> XPathValue {
+        let expr = expr.trim();
+        if let Some(path) = expr.strip_prefix("count(").and_then(|x| x.strip_suffix(")")) {
+            XPathValue::Number(self.find_at_iter(path.trim(), context).count() as f64)
+        } else if let Some(path) = expr.strip_prefix("boolean(").and_then(|x| x.strip_suffix(")")) {
+            XPathValue::Boolean(self.find_at(path.trim(), context).is_some())
+        } else if let Some(path) = expr.strip_prefix("string(").and_then(|x| x.strip_suffix(")")) {
+            let value = match self.evaluate(path, context) {
+                XPathValue::Nodes(nodes) => nodes.first().and_then(|&x| self.inner_text(x)),
+                XPathValue::Strings(values) => values.into_iter().next(),
+                _ => None,
+            };
+            XPathValue::String(value.unwrap_or_default())
+        } else {
+            let iter = self.find_at_iter(expr, context);
+            match iter.attr_name().map(|x| x.to_string()) {
+                Some(attr) => XPathValue::Strings(iter.filter_map(|x| self.data[x].get_attr(&attr)).collect()),
+                None => XPathValue::Nodes(iter.collect()),
+            }
+        }
+    }
+    #[allow(dead_code)]
+    /// get own text of the node of specified position with XML whitespace (space, tab, CR, LF) trimmed and
+    /// runs of it collapsed into a single space, as `normalize-space()` does
+    pub fn normalized_text(&self, pos:usize) -> String {
+        let text = self.data.get(pos).and_then(|x| x.text_ref()).unwrap_or("");
+        text.split([' ', '\t', '\r', '\n']).filter(|x| !x.is_empty()).collect::<Vec<_>>().join(" ")
+    }
+    #[allow(dead_code)]
+    /// concatenate text of the node of specified position and all descendant elements and CDATA sections
+    pub fn inner_text(&self, pos:usize) -> Option<String> {
+        let mut out = self.data.get(pos)?.text_ref().unwrap_or("").to_string();
+        for i in self.descendant(pos) {
+            if self.data[i].get_localname() == "<CData>" || !self.data[i].is_special() {
+                out.push_str(self.data[i].text_ref().unwrap_or(""));
+            }
+            out.push_str(self.data[i].tail_ref());
+        }
+        Some(out)
+    }
+    #[allow(dead_code)]
+    /// join `inner_text` of all nodes that matches `path` from the root node with `sep`
+    pub fn text_of(&self, path:&str, sep:&str) -> String {
+        self.find_iter(path).filter_map(|x| self.inner_text(x)).collect::<Vec<_>>().join(sep)
+    }
+}
+
+/// Result of `ETree::evaluate`
+#[derive(Debug, Clone, PartialEq)]
+pub enum XPathValue {
+    /// positions of matched nodes
+    Nodes(Vec<usize>),
+    /// attr values of matched nodes
+    Strings(Vec<String>),
+    String(String),
+    Number(f64),
+    Boolean(bool),
 }
 
 /// transform root node into a tree
 impl From<ETreeNode> for ETree {
     fn from(mut node:ETreeNode) -> Self {
         let mut tree = ETree {
-            indent:"".to_string(),
             count:1,
-            version:"1.0".to_string().into_bytes(),
-            encoding:None,
-            standalone:None,
-            data:Vec::new(),
-            crlf:"".to_string(),
-            enable_index: false,
-            index: HashMap::new(),
+            ..ETree::empty()
         };
         node.set_idx(0);
         node.set_route("#");
         tree.data.push(node);
+        tree.generate_index();
         tree
     }
 }
 
+/// consume the tree and yield nodes in document order
+impl IntoIterator for ETree {
+    type Item = ETreeNode;
+    type IntoIter = std::vec::IntoIter<ETreeNode>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.into_iter()
+    }
+}
+
+/// parse XML text into a tree
+impl std::str::FromStr for ETree {
+    type Err = ETreeError;
+    fn from_str(content:&str) -> Result<Self, Self::Err> {
+        ETree::parse_str_checked(content)
+    }
+}
+
+/// serialize the tree into XML text
+///
+/// Text is always written as UTF-8, the same as `write_file`, regardless of the declared encoding.
+/// A corrupt tree fails with `std::fmt::Error`, use `write_with` to get the error.
+impl std::fmt::Display for ETree {
+    fn fmt(&self, f:&mut std::fmt::Formatter) -> std::fmt::Result {
+        let data = self.write().map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", String::from_utf8_lossy(&data))
+    }
+}
+
+/// serialize the tree with children nested in their parent node
+#[cfg(feature = "serde")]
+mod serde_support {
+    use std::collections::HashMap;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use super::{ETree, ETreeNode};
+
+    #[derive(Serialize, Deserialize)]
+    struct NestedNode {
+        #[serde(flatten)]
+        node:ETreeNode,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        children:Vec<NestedNode>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NestedTree {
+        version:Option<String>,
+        #[serde(default)]
+        declared:bool,
+        #[serde(default)]
+        decl_tail:Option<String>,
+        encoding:Option<String>,
+        standalone:Option<String>,
+        indent:String,
+        crlf:String,
+        #[serde(default)]
+        resolve_entities:bool,
+        #[serde(default)]
+        entities:HashMap<String, String>,
+        #[serde(default)]
+        namespaces:HashMap<String, String>,
+        nodes:Vec<NestedNode>,
+    }
+
+    fn nest(tree:&ETree, pos:usize) -> NestedNode {
+        NestedNode {
+            node:tree.data[pos].clone(),
+            children:tree.children_iter(pos).map(|x| nest(tree, x)).collect(),
+        }
+    }
+
+    fn flatten(tree:&mut ETree, nodes:Vec<NestedNode>, route:&str) {
+        for item in nodes {
+            let mut node = item.node;
+            let idx = tree.count;
+            node.set_idx(idx);
+            node.set_route(route);
+            tree.data.push(node);
+            tree.count += 1;
+            flatten(tree, item.children, &format!("{}{}#", route, idx));
+        }
+    }
+
+    impl Serialize for ETree {
+        fn serialize<S:Serializer>(&self, serializer:S) -> Result<S::Ok, S::Error> {
+            NestedTree {
+                version:self.get_version(),
+                declared:self.declared,
+                decl_tail:self.decl_tail.clone(),
+                encoding:self.get_encoding(),
+                standalone:self.get_standalone(),
+                indent:self.indent.clone(),
+                crlf:self.crlf.clone(),
+                resolve_entities:self.resolve_entities,
+                entities:self.entities.clone(),
+                namespaces:self.namespaces.clone(),
+                nodes:(0..self.data.len()).filter(|&x| self.data[x].get_route() == "#").map(|x| nest(self, x)).collect(),
+            }.serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for ETree {
+        fn deserialize<D:Deserializer<'de>>(deserializer:D) -> Result<Self, D::Error> {
+            let nested = NestedTree::deserialize(deserializer)?;
+            let mut tree = ETree {
+                indent:nested.indent,
+                version:nested.version.unwrap_or_default().into_bytes(),
+                declared:nested.declared,
+                decl_tail:nested.decl_tail,
+                encoding:nested.encoding.map(|x| x.into_bytes()),
+                standalone:nested.standalone.map(|x| x.into_bytes()),
+                crlf:nested.crlf,
+                resolve_entities:nested.resolve_entities,
+                entities:nested.entities,
+                namespaces:nested.namespaces,
+                ..ETree::empty()
+            };
+            flatten(&mut tree, nested.nodes, "#");
+            tree.generate_index();
+            Ok(tree)
+        }
+    }
+}
+
 /// XPath operation
 ///
 /// # Supported syntax:
 /// ## Node query
 /// - `nodename`: the same as `//nodename`
-/// - `*`: any node
+/// - `*`: any element node (comment, PI and CDATA nodes are never matched)
 /// - `/`: node in the children of current node
 /// - `//`: node in the descendant of current node
 /// - `.`: current node
 /// - `..`: parent node
-/// - `@attrname`
+/// - tag names are compared with `ETreeNode::get_name`, i.e. `prefix:localname`;
+///   `ETree::find_local_iter` compares local names and ignores prefixes in `path`
+/// - an element in a default namespace (`xmlns="uri"`) has no prefix, so `//foo` matches `foo` in any default namespace or in none;
+///   `ETree::find_ns_iter` compares namespace uri of prefixes registered by `ETree::register_namespace` instead
+/// - `@attrname`: as the first step, any element with attr `attrname` (the same as `//*[@attrname]`);
+///   as the last step, the element selected by previous steps if it has attr `attrname`, e.g. `//bean/@id` matches `bean` with `id`
+///   (use `ETree::find_values` or `ETree::evaluate` to get the attr values)
 /// ## Node Predicate
-/// - `[1]`: first element
+/// - `[1]`: first element (position is counted among the nodes matching the node query under the same parent,
+///   e.g. `row[3]` is the third `row` and `*[3]` is the third element, `//section/para[1]` is the first `para` of each `section`)
 /// - `[last()-1]`: second to last element
 /// - `[position() < 3]`: first and second element
+/// - `[position() >= 2 and position() <= 4]`: second to fourth element
+/// - `[position() > last() - 2]`: last two elements
 /// - `[@attrname]`: element with attr `attrname`
 /// - `[@*]`: element with any attr
 /// - `[@attrname='value']`: element with attr `attrname`=`value`
+/// - `[@attrname='O\'Brien']`: `'` and `\` in value are escaped by `\`
+/// - `[@attrname>'10']`: `>`, `<`, `>=` and `<=` compare numbers, non-numeric values don't match
 /// - `[text()='value']`: element which text is equal to `value`
 /// - `[child-tag='value']`: element which contains child `child-tag` and child tag's text is equal to `value`
+/// - `[@attrname='']`: element with empty attr `attrname`, element without the attr doesn't match
+/// - `[text()='']`: element without text
+/// - `[not(@attrname)]`: element without attr `attrname`, `not(...)` negates any condition
 /// - `[text()='value' and child-tag='value']`: multiple condition with `and`/`or` and parenthesis
 /// # Search algorithm
-/// 1. `path` is split into multiple parts by consecutive "/".
-///    - e.g. "//tag1/tag2[text()='abc']" is split into ["//tag1", "/tag2[text()='abc']"]
-/// 2. find first part from the specified node
-/// 3. find next part from the result of last find
-/// 4. repeat step 3 until all part finished
+/// 1. `path` is parsed by the grammar in `xpath` module into segments of separator, node and predicate.
+///    - e.g. "//tag1/tag2[text()='abc']" is parsed into [("//", "tag1", None), ("/", "tag2", text()=='abc')]
+/// 2. find first segment from the specified node
+/// 3. find next segment from the result of last find, the predicate is evaluated with `Predictor::eval`
+/// 4. repeat step 3 until all segments finished
 pub struct XPathIterator<'a> {
     tree: &'a ETree,
     direction: bool,
     path_list: Vec<xpath::XPathSegment>,
+    param_list: Vec<(Vec<String>, Vec<String>)>,
     todo_list: Vec<(usize, usize)>,
+    name_match: NameMatch,
+    /// first step matches top level nodes of the document instead of children of the context node
+    absolute: bool,
+    /// (position, step) already searched, a node reached by several ways like `//*//c` is only yielded once
+    visited: HashSet<(usize, usize)>,
+}
+
+/// how tag names in path are compared with nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NameMatch {
+    /// `prefix:localname` as written in the document
+    Name,
+    /// local name, prefixes are ignored
+    LocalName,
+    /// local name and namespace uri registered for the prefix, no prefix means no namespace
+    Namespace,
 }
 
 impl<'a> XPathIterator<'a> {
     #[allow(dead_code)]
     fn new(tree:&'a ETree, path:&str, pos:usize, dir:bool) -> Self {
-        let (remaining, mut path_todo) = xpath::xpath(path).unwrap();
-        debug_assert_eq!(remaining, "");
-        if path_todo[0].separator == "" {
-            if path_todo[0].node == "." {
+        Self::try_new(tree, path, pos, dir).unwrap_or(Self {
+            tree,
+            direction: dir,
+            path_list: Vec::new(),
+            param_list: Vec::new(),
+            todo_list: Vec::new(),
+            name_match: NameMatch::Name,
+            absolute: false,
+            visited: HashSet::new(),
+        })
+    }
+    fn try_new(tree:&'a ETree, path:&str, pos:usize, dir:bool) -> Result<Self, XPathError> {
+        let mut path_todo = match xpath::xpath(path) {
+            Ok(("", segments)) if !segments.is_empty() => segments,
+            Ok((remaining, _)) => return Err(XPathError { path:path.to_string(), position:path.len() - remaining.len() }),
+            Err(_) => return Err(XPathError { path:path.to_string(), position:0 }),
+        };
+        if path_todo[0].separator.is_empty() {
+            if path_todo[0].node.starts_with('@') {
+                // leading attr step is the same as `//*[@name]`
+                path_todo[0].condition = xpath::Predictor::Condition(path_todo[0].node.clone(), None, None);
+                path_todo[0].node = "*".to_string();
+                path_todo[0].separator = "//".to_string();
+            } else if path_todo[0].node == "." {
                 path_todo.remove(0);
             } else if path_todo[0].node == ".." {
                 path_todo[0].separator = "/".to_string();
@@ -1050,14 +2805,25 @@ impl<'a> XPathIterator<'a> {
                 path_todo[0].separator = "//".to_string();
             }
         }
-        Self {
-            tree: tree,
+        // child and attribute names used in predicates, collected once per segment
+        let param_todo = path_todo.iter().map(|x| {
+            let (c, mut a, _) = x.condition.collect();
+            a.retain(|x| x != "*");
+            (c, a)
+        }).collect();
+        Ok(Self {
+            tree,
             direction: dir,
             path_list: path_todo,
-            todo_list: vec![(pos, 0)],
-        }
+            param_list: param_todo,
+            todo_list: if pos < tree.data.len() { vec![(pos, 0)] } else { Vec::new() },
+            name_match: NameMatch::Name,
+            absolute: false,
+            visited: HashSet::new(),
+        })
     }
-    fn _find(&self, path:&xpath::XPathSegment, pos:usize) -> Vec<usize> {
+    fn _find(&self, step:usize, pos:usize) -> Vec<usize> {
+        let path = &self.path_list[step];
         let mut result:Vec<usize> = Vec::new();
         if path.separator == "/" && path.node == "." {
             result.push(pos);
@@ -1065,63 +2831,78 @@ impl<'a> XPathIterator<'a> {
             if let Some(parent) = self.tree.parent(pos) {
                 result.push(parent);
             }
+        } else if let Some(attr) = path.node.strip_prefix('@') {
+            // attr step selects the owner element, `//@name` also searches descendants
+            let mut container = vec![pos];
+            if path.separator == "//" {
+                container.append(&mut self.tree.descendant(pos));
+            }
+            result = container.into_iter().filter(|&x| self.tree.node(x).unwrap().get_attr(attr).is_some()).collect();
         } else {
-            let container = if path.separator == "//" {
+            let container = if step == 0 && self.absolute {
+                (0..self.tree.data.len()).filter(|&x| path.separator == "//" || self.tree.data[x].get_route() == "#").collect()
+            } else if path.separator == "//" {
                 self.tree.descendant(pos)
             } else { /* "/" */
                 self.tree.children(pos)
             };
             let mut container = if path.node == "*" {
-                container.clone()
+                // comment, PI, CDATA and DOCTYPE nodes are not elements
+                container.iter().filter(|&x| !self.tree.data[*x].is_special()).copied().collect()
             } else {
-                container.iter().filter(|&x| self.tree.node(*x).unwrap().get_name()==path.node).map(|x| *x).collect()
+                container.iter().filter(|&x| self.name_matches(*x, &path.node)).copied().collect()
             };
             if path.condition == xpath::Predictor::None {
                 result.append(&mut container);
             } else {
-                let (c, mut a, _) = path.condition.collect();
-                if let Some(idx) = a.iter().position(|x| x=="*") {
-                    a.remove(idx);
+                let (c, a) = &self.param_list[step];
+                // position and last are counted among the matching children of the same parent
+                let mut sibling_count:HashMap<String, usize> = HashMap::new();
+                for &cur in container.iter() {
+                    *sibling_count.entry(self.tree.data[cur].get_route()).or_insert(0) += 1;
                 }
-                let container_len = container.len();
-                for i in 0..container_len {
+                let mut sibling_position:HashMap<String, usize> = HashMap::new();
+                for &cur in container.iter() {
+                    let route = self.tree.data[cur].get_route();
+                    let position = sibling_position.entry(route.clone()).or_insert(0);
+                    *position += 1;
+                    let position = *position;
                     let mut info = HashMap::new();
-                    if self.tree.node(container[i]).unwrap().get_attr_count() > 0 {
+                    // absent attrs are not inserted so that their conditions are false
+                    if self.tree.node(cur).unwrap().get_attr_count() > 0 {
                         info.insert("@*".to_string(), "true".to_string());
                         for param in a.iter() {
-                            if let Some(v) = self.tree.node(container[i]).unwrap().get_attr(param) {
+                            if let Some(v) = self.tree.node(cur).unwrap().get_attr(param) {
                                 info.insert(format!("@{}", param), v);
                             }
                         }
-                    } else {
-                        info.insert("@*".to_string(), "false".to_string());
                     }
-                    info.insert("text()".to_string(), self.tree.node(container[i]).unwrap().get_text().unwrap_or("".to_string()));
-                    info.insert("position()".to_string(), format!("{}", i+1));
-                    info.insert("last()".to_string(), format!("{}", container_len));
-                    if c.len() > 0 {
+                    info.insert("text()".to_string(), self.tree.node(cur).unwrap().get_text().unwrap_or("".to_string()));
+                    info.insert("position()".to_string(), format!("{}", position));
+                    info.insert("last()".to_string(), format!("{}", sibling_count[&route]));
+                    if !c.is_empty() {
                         let mut subfound:Vec<Vec<usize>> = Vec::new();
                         let mut curcomb:Vec<usize> = Vec::new();
                         for _ in 0..c.len() {
                             subfound.push(Vec::new());
                             curcomb.push(0);
                         }
-                        let subchildren = self.tree.children(container[i]);
+                        let subchildren = self.tree.children(cur);
                         for subi in subchildren {
                             for subj in 0..c.len() {
-                                if self.tree.node(subi).unwrap().get_name() == c[subj] {
+                                if self.name_matches(subi, &c[subj]) {
                                     subfound[subj].push(subi);
                                 }
                             }
                         }
-                        if subfound.iter().all(|ref x| x.len() > 0) {
+                        if subfound.iter().all(|x| !x.is_empty()) {
                             let mut exit_flag = false;
                             loop {
                                 for subj in 0..c.len() {
                                     info.insert(c[subj].clone(), self.tree.node(subfound[subj][curcomb[subj]]).unwrap().get_text().unwrap_or("".to_string()));
                                 }
-                                if eval::eval(path.condition.expr(&info).as_str()) == Ok(eval::to_value(true)) {
-                                    result.push(container[i]);
+                                if path.condition.eval(&info) {
+                                    result.push(cur);
                                     break;
                                 }
                                 let mut subi = curcomb.len() - 1;
@@ -1145,8 +2926,8 @@ impl<'a> XPathIterator<'a> {
                             }
                         }
                     } else {
-                        if eval::eval(path.condition.expr(&info).as_str()) == Ok(eval::to_value(true)) {
-                            result.push(container[i]);
+                        if path.condition.eval(&info) {
+                            result.push(cur);
                         }
                     }
                 }
@@ -1156,15 +2937,53 @@ impl<'a> XPathIterator<'a> {
     }
 }
 
+impl<'a> XPathIterator<'a> {
+    fn with_name_match(mut self, name_match:NameMatch) -> Self {
+        self.name_match = name_match;
+        self
+    }
+    fn with_absolute(mut self) -> Self {
+        self.absolute = true;
+        self
+    }
+    fn name_matches(&self, pos:usize, name:&str) -> bool {
+        let node = self.tree.node(pos).unwrap();
+        let (prefix, localname) = name.rsplit_once(':').unwrap_or(("", name));
+        match self.name_match {
+            NameMatch::Name => node.get_name() == name,
+            NameMatch::LocalName => node.get_localname() == localname,
+            NameMatch::Namespace => {
+                let uri = if prefix.is_empty() {
+                    Some("")
+                } else {
+                    self.tree.namespace_uri(prefix)
+                };
+                node.get_localname() == localname && uri == Some(node.get_namespace().as_str())
+            },
+        }
+    }
+    /// name of attr if the last step is an attr step
+    fn attr_name(&self) -> Option<&str> {
+        let last = self.path_list.last()?;
+        if last.separator.is_empty() {
+            None
+        } else {
+            last.node.strip_prefix('@')
+        }
+    }
+}
+
 impl<'a> Iterator for XPathIterator<'a> {
     type Item = usize;
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.todo_list.is_empty() {
-            let item = self.todo_list.pop().unwrap();
+        while let Some(item) = self.todo_list.pop() {
+            if !self.visited.insert(item) {
+                continue;
+            }
             if item.1 >= self.path_list.len() {
                 return Some(item.0);
             } else {
-                let result = self._find(&self.path_list[item.1], item.0);
+                let result = self._find(item.1, item.0);
                 let rlen = result.len();
                 let mut ridx = rlen;
                 if self.direction {
@@ -1183,3 +3002,1397 @@ impl<'a> Iterator for XPathIterator<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_cdata_roundtrip() {
+        let tree = ETree::parse_str("<root><![CDATA[a < b && c > d &amp;]]></root>");
+        let pos = tree.children(tree.root())[0];
+        assert_eq!(tree.node(pos).unwrap().get_text().as_deref(), Some("a < b && c > d &amp;"));
+        let output = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(output.ends_with("<root><![CDATA[a < b && c > d &amp;]]></root>"));
+    }
+    #[test]
+    fn test_resolve_entities() {
+        let content = "<!DOCTYPE root [\n<!ENTITY foo \"bar\">\n]>\n<root attr=\"&foo;\">&foo; &amp; &lt;baz&gt;</root>";
+        let mut tree = ETree::parse_str(content);
+        assert!(!tree.get_resolve_entities());
+        let pos = tree.root();
+        assert_eq!(tree.node(pos).unwrap().get_text().as_deref(), Some("&foo; & <baz>"));
+        let output = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(output.ends_with("<root attr=\"&foo;\">&foo; &amp; &lt;baz&gt;</root>"));
+        tree.set_resolve_entities(true);
+        assert_eq!(tree.node(pos).unwrap().get_text().as_deref(), Some("bar & <baz>"));
+        assert_eq!(tree.node(pos).unwrap().get_attr("attr").as_deref(), Some("bar"));
+        let output = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(output.contains("<!ENTITY foo \"bar\">"));
+        assert!(output.ends_with("<root attr=\"bar\">bar &amp; &lt;baz&gt;</root>"));
+        // a literal `&foo;` stays literal text while the reference next to it is kept or resolved
+        let content = "<!DOCTYPE r [<!ENTITY foo \"bar\">]><r a=\"&amp;foo; &foo;\">\n  <b>&amp;foo; &foo;</b>\n</r>";
+        let mut tree = ETree::parse_str(content);
+        let pos = tree.find("/b").unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_text().as_deref(), Some("&foo; &foo;"));
+        let write = |tree:&ETree| String::from_utf8(tree.write_with(&WriteOptions::new().declaration(false)).unwrap()).unwrap();
+        assert_eq!(write(&tree), content);
+        tree.set_line_ending("\r\n");
+        tree.replace_text_all("foo", "baz", true);
+        assert_eq!(write(&tree), "<!DOCTYPE r [<!ENTITY foo \"bar\">]><r a=\"&amp;baz; &foo;\">\r\n  <b>&amp;baz; &foo;</b>\r\n</r>");
+        tree.set_resolve_entities(true);
+        assert_eq!(tree.node(pos).unwrap().get_text().as_deref(), Some("&baz; bar"));
+        assert_eq!(tree.node(tree.root()).unwrap().get_attr("a").as_deref(), Some("&baz; bar"));
+        let tree = ETree::parse_with(content, &ReadOptions::new().resolve_entities(true)).unwrap();
+        assert_eq!(tree.node(tree.find("/b").unwrap()).unwrap().get_text().as_deref(), Some("&foo; bar"));
+        let mut tree = ETree::parse_str("<!DOCTYPE r [<!ENTITY foo \"bar\">]><r> &foo; <c/></r>");
+        tree.pretty("\n  ");
+        assert_eq!(write(&tree), "<!DOCTYPE r [<!ENTITY foo \"bar\">]>\n<r>&foo;\n  <c/>\n</r>\n");
+    }
+    #[test]
+    fn test_children_iter() {
+        let tree = ETree::parse_str("<root><a><x/></a><b/><c/></root><!-- end -->");
+        let pos = tree.root();
+        assert_eq!(tree.children_iter(pos).collect::<Vec<usize>>(), vec![1, 3, 4]);
+        assert_eq!(tree.children_iter(pos).take(2).collect::<Vec<usize>>(), vec![1, 3]);
+        assert_eq!(tree.children_iter(1).collect::<Vec<usize>>(), vec![2]);
+        assert_eq!(tree.children_iter(4).count(), 0);
+        assert_eq!(tree.children_iter(100).count(), 0);
+    }
+    #[test]
+    fn test_index_navigation() {
+        let content = "<root><a><x/><y/></a><b/><!-- c --><d><z/></d></root>";
+        let mut tree = ETree::parse_str(content);
+        let plain:Vec<_> = (0..tree.data.len()).map(|i| (tree.parent(i), tree.previous(i), tree.next(i))).collect();
+        tree.set_enable_index(true);
+        let indexed:Vec<_> = (0..tree.data.len()).map(|i| (tree.parent(i), tree.previous(i), tree.next(i))).collect();
+        assert_eq!(plain, indexed);
+        let pos = tree.find("//b").unwrap();
+        tree.append_next_node(pos, ETreeNode::new("e"));
+        tree.remove(tree.find("//x").unwrap());
+        let indexed:Vec<_> = (0..tree.data.len()).map(|i| (tree.parent(i), tree.previous(i), tree.next(i))).collect();
+        tree.set_enable_index(false);
+        let plain:Vec<_> = (0..tree.data.len()).map(|i| (tree.parent(i), tree.previous(i), tree.next(i))).collect();
+        assert_eq!(plain, indexed);
+    }
+    #[test]
+    fn test_subtree_reindex() {
+        let mut tree = ETree::parse_str("<root><a><b><c/></b><d/></a></root>");
+        let mut subtree = tree.subtree(tree.find("//a").unwrap());
+        assert_eq!(subtree.subtree_reindex(2), (9, 13));
+        assert_eq!(subtree.subtree_reindex(10), (10, 14));
+        let routes:Vec<(usize, String)> = subtree.data.iter().map(|x| (x.get_idx(), x.get_route())).collect();
+        assert_eq!(routes, vec![
+            (10, "#".to_string()),
+            (11, "#10#".to_string()),
+            (12, "#10#11#".to_string()),
+            (13, "#10#".to_string()),
+        ]);
+        let root = tree.root();
+        tree.append_child_tree(root, subtree);
+        let output = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(output.ends_with("<root><a><b><c/></b><d/></a><a><b><c/></b><d/></a></root>"));
+    }
+    #[test]
+    fn test_find_predicate() {
+        let tree = ETree::parse_str("<root><item id='1'><name>a</name></item><item id='2' kind='x'><name>b</name></item><item><name>c</name></item></root>");
+        let names = |path:&str| -> Vec<String> {
+            tree.find_iter(path).map(|x| tree.node(x).unwrap().get_attr("id").unwrap_or("".to_string())).collect()
+        };
+        assert_eq!(names("//item[@id]"), vec!["1", "2"]);
+        assert_eq!(names("//item[@id='2' and @kind]"), vec!["2"]);
+        assert_eq!(names("//item[name='c' or @id='1']"), vec!["1", ""]);
+        assert_eq!(names("/item[2]"), vec!["2"]);
+    }
+    #[test]
+    fn test_find_checked() {
+        let tree = ETree::parse_str("<root><node attr='a'/></root>");
+        assert_eq!(tree.find_checked("//node[@attr='a']").unwrap().count(), 1);
+        assert_eq!(tree.find_checked("//other").unwrap().count(), 0);
+        let err = tree.find_checked("//node[@attr=").err().unwrap();
+        assert_eq!(err.position, 6);
+        assert_eq!(tree.find_checked("").err().unwrap().position, 0);
+        assert_eq!(tree.find("//node[@attr="), None);
+        assert_eq!(tree.find(""), None);
+    }
+    #[test]
+    fn test_position_range() {
+        let tree = ETree::parse_str("<root><item>1</item><item>2</item><item>3</item><item>4</item><item>5</item></root>");
+        let texts = |path:&str| -> Vec<String> {
+            tree.find_iter(path).map(|x| tree.node(x).unwrap().get_text().unwrap()).collect()
+        };
+        assert_eq!(texts("//item[position() >= 2 and position() <= 4]"), vec!["2", "3", "4"]);
+        assert_eq!(texts("//item[position() > 1 and position() < 2]"), Vec::<String>::new());
+        assert_eq!(texts("//item[position() < 3]"), vec!["1", "2"]);
+        assert_eq!(texts("//item[position() = 5]"), vec!["5"]);
+        assert_eq!(texts("//item[position() > last() - 2]"), vec!["4", "5"]);
+        assert_eq!(texts("//item[position() >= 2 and position() < last()]"), vec!["2", "3", "4"]);
+        assert_eq!(texts("//item[position() = last()]"), vec!["5"]);
+    }
+    #[test]
+    fn test_step_predicate() {
+        let tree = ETree::parse_str("<doc><section id='intro'><para>a</para><para>b</para></section><section id='body'><para>c</para><para>d</para><para>e</para></section></doc>");
+        let texts = |path:&str| -> Vec<String> {
+            tree.find_iter(path).map(|x| tree.node(x).unwrap().get_text().unwrap()).collect()
+        };
+        assert_eq!(texts("//section[@id='intro']/para[1]"), vec!["a"]);
+        assert_eq!(texts("//section[@id='body']/para[2]"), vec!["d"]);
+        assert_eq!(texts("//section/para[1]"), vec!["a", "c"]);
+        assert_eq!(texts("//section/para[last()]"), vec!["b", "e"]);
+        assert_eq!(texts("//para[position() > 1]"), vec!["b", "d", "e"]);
+        assert_eq!(texts("/section[2]/para[last()-1]"), vec!["d"]);
+    }
+    #[test]
+    fn test_last_index() {
+        let tree = ETree::parse_str("<root><item>1</item><item>2</item><group><item>a</item></group><item>3</item></root>");
+        let texts = |path:&str| -> Vec<String> {
+            tree.find_iter(path).map(|x| tree.node(x).unwrap().get_text().unwrap()).collect()
+        };
+        assert_eq!(texts("//item[last()]"), vec!["a", "3"]);
+        assert_eq!(texts("//item[last()-0]"), vec!["a", "3"]);
+        assert_eq!(texts("//item[last()-1]"), vec!["2"]);
+        assert_eq!(texts("//item[last() - 2]"), vec!["1"]);
+        assert_eq!(texts("//item[last()-3]"), Vec::<String>::new());
+        assert_eq!(texts("//item[last()-9]"), Vec::<String>::new());
+        assert_eq!(texts("/item[last()-1]"), vec!["2"]);
+        assert_eq!(texts("/group/item[last()-1]"), Vec::<String>::new());
+        assert_eq!(tree.rfind("//item[last()]").and_then(|x| tree.node(x).unwrap().get_text()).as_deref(), Some("3"));
+    }
+    #[test]
+    fn test_escaped_quote() {
+        let tree = ETree::parse_str("<root><node name=\"O'Brien\">O'Brien</node><node name=\"OBrien\">a\\b</node><node name=\"O\\'Brien\"/></root>");
+        let names = |path:&str| -> Vec<String> {
+            tree.find_iter(path).map(|x| tree.node(x).unwrap().get_attr("name").unwrap()).collect()
+        };
+        assert_eq!(names("//node[@name='O\\'Brien']"), vec!["O'Brien"]);
+        assert_eq!(names("//node[text()='O\\'Brien']"), vec!["O'Brien"]);
+        assert_eq!(names("//node[@name!='O\\'Brien']"), vec!["OBrien", "O\\'Brien"]);
+        assert_eq!(names("//node[@name='O\\\\\\'Brien']"), vec!["O\\'Brien"]);
+        assert_eq!(names("//node[text()='a\\\\b']"), vec!["OBrien"]);
+    }
+    #[test]
+    fn test_predicate_injection() {
+        let tree = ETree::parse_str("<root><user name=\"a' || '1' == '1\"><role>x') || ('1' == '1</role></user><user name=\"b\\\"><role>y\\</role></user><user name=\"1 &gt; 0\"><role>z</role></user></root>");
+        assert_eq!(tree.find_iter("//user[@name='admin']").count(), 0);
+        assert_eq!(tree.find_iter("//user[role='admin']").count(), 0);
+        assert_eq!(tree.find_iter("//user[@name='b' or role='admin']").count(), 0);
+        assert_eq!(tree.find_iter("//user[@name>'0']").count(), 0);
+        assert_eq!(tree.find_iter("//user[@name!='admin']").count(), 3);
+        assert_eq!(tree.find_iter("//user[@name='a\\' || \\'1\\' == \\'1']").count(), 1);
+        assert_eq!(tree.find_iter("//user[@name='b\\\\' and role='y\\\\']").count(), 1);
+    }
+    #[test]
+    fn test_wildcard_index() {
+        let tree = ETree::parse_str("<table><!-- head --><row>1</row><note>x</note><?pi data?><row>2</row><row>3</row></table>");
+        let text = |path:&str| tree.find(path).and_then(|x| tree.node(x).unwrap().get_text());
+        assert_eq!(text("/row[3]").as_deref(), Some("3"));
+        assert_eq!(text("/*[1]").as_deref(), Some("1"));
+        assert_eq!(text("/*[2]").as_deref(), Some("x"));
+        assert_eq!(text("/*[3]").as_deref(), Some("2"));
+        assert_eq!(text("/*[last()]").as_deref(), Some("3"));
+        assert_eq!(tree.find_iter("/*").count(), 4);
+    }
+    #[test]
+    fn test_wildcard_step() {
+        let tree = ETree::parse_str("<root><a><x><c>1</c></x><!--c--><y><c>2</c><d><c>3</c></d></y></a><b id=\"b1\"><z id=\"z1\"><c>4</c></z></b></root>");
+        let texts = |path:&str| -> Vec<String> {
+            tree.find_iter(path).map(|x| tree.node(x).unwrap().get_text().unwrap()).collect()
+        };
+        let ids = |path:&str| -> Vec<String> {
+            tree.find_iter(path).map(|x| tree.node(x).unwrap().get_attr("id").unwrap()).collect()
+        };
+        assert_eq!(texts("/a/*/c"), vec!["1", "2"]);
+        assert_eq!(texts("/*/*/c"), vec!["1", "2", "4"]);
+        assert_eq!(texts("/a/*/*/c"), vec!["3"]);
+        assert_eq!(texts("//*/c"), vec!["1", "2", "3", "4"]);
+        assert_eq!(texts("/a//*/c"), vec!["1", "2", "3"]);
+        assert_eq!(texts("//*//c"), vec!["1", "2", "3", "4"]);
+        assert_eq!(texts("/*[2]/*/c"), vec!["4"]);
+        assert_eq!(texts("/a/*[2]/c"), vec!["2"]);
+        assert_eq!(tree.find_absolute_iter("/root/*/*/c").count(), 3);
+        assert_eq!(ids("//*/@id"), vec!["b1", "z1"]);
+        assert_eq!(ids("/*/@id"), vec!["b1"]);
+        assert_eq!(ids("/*/*/@id"), vec!["z1"]);
+        assert_eq!(tree.find_values("//*/@id"), vec!["b1", "z1"]);
+    }
+    #[test]
+    fn test_into_iter() {
+        let tree = ETree::parse_str("<root><a/><!-- b --><c>d</c></root>");
+        let names:Vec<String> = tree.into_iter().map(|x| x.get_localname()).collect();
+        assert_eq!(names, vec!["root", "a", "<Comment>", "c"]);
+    }
+    #[test]
+    fn test_from_str() {
+        let tree:ETree = "<root><a>b</a></root>".parse().unwrap();
+        assert_eq!(tree.node(tree.find("//a").unwrap()).unwrap().get_text().as_deref(), Some("b"));
+        let result:Result<ETree, ETreeError> = "<root>&bogus;</root>".parse();
+        assert!(matches!(result, Err(ETreeError::Xml { .. })));
+    }
+    #[test]
+    fn test_display() {
+        let mut tree = ETree::from(ETreeNode::new("root"));
+        let root = tree.root();
+        let mut node = ETreeNode::new("a");
+        node.set_text("b & c");
+        tree.append_child_node(root, node);
+        assert_eq!(tree.to_string(), "<?xml version=\"1.0\"?><root><a>b &amp; c</a></root>");
+        assert_eq!(format!("{}", tree), String::from_utf8(tree.write().unwrap()).unwrap());
+    }
+    #[test]
+    fn test_extract_nodes() {
+        let tree = ETree::parse_str("<root><a><b><c/></b></a><d/></root>");
+        let nodes = tree.extract_nodes(tree.find("//b").unwrap());
+        let routes:Vec<(String, String)> = nodes.iter().map(|x| (x.get_localname(), x.get_route())).collect();
+        assert_eq!(routes, vec![("b".to_string(), "#".to_string()), ("c".to_string(), "#2#".to_string())]);
+        assert!(tree.extract_nodes(100).is_empty());
+    }
+    #[test]
+    fn test_set_element_text() {
+        let mut tree = ETree::parse_str("<root><a>x<b/>y<!--c--><d>z</d></a><e/></root>");
+        let pos = tree.find("//a").unwrap();
+        tree.set_element_text(pos, "1 < 2");
+        assert!(tree.children(pos).is_empty());
+        assert_eq!(tree.find("//e"), Some(pos + 1));
+        assert!(tree.to_string().ends_with("<root><a>1 &lt; 2</a><e/></root>"));
+    }
+    #[test]
+    fn test_child_text() {
+        let tree = ETree::parse_str("<config><name>a</name><name>b</name><port/></config>");
+        let root = tree.root();
+        assert_eq!(tree.child_text(root, "name"), Some("a".to_string()));
+        assert_eq!(tree.child_text(root, "host"), None);
+        assert_eq!(tree.child_text(100, "name"), None);
+    }
+    #[test]
+    fn test_find_all() {
+        let tree = ETree::parse_str("<root><a/><b><a/></b><a/></root>");
+        assert_eq!(tree.find_all("//a"), vec![1, 3, 4]);
+        assert_eq!(tree.find_all("/a"), vec![1, 4]);
+        assert!(tree.find_all("//c").is_empty());
+    }
+    #[test]
+    fn test_out_of_range() {
+        let mut tree = ETree::parse_str("<root/>");
+        assert_eq!(tree.next(0), None);
+        assert_eq!(tree.previous(0), None);
+        assert_eq!(tree.next(5), None);
+        assert_eq!(tree.parent(5), None);
+        assert!(tree.children(5).is_empty());
+        assert!(tree.node_mut(5).is_none());
+        assert_eq!(tree.find_at(".", 5), None);
+        assert_eq!(tree.append_child_node(5, ETreeNode::new("a")), None);
+        tree.remove(5);
+        assert_eq!(tree.find_all("//*").len(), 0);
+        let empty = tree.subtree(5);
+        assert_eq!(tree.append_child_tree(0, empty), None);
+        assert_eq!(tree.append_child_tree(0, ETree::from(ETreeNode::new("a"))), Some(1));
+        tree.remove(0);
+        assert_eq!(tree.next(0), None);
+        assert_eq!(tree.find("."), None);
+        tree.write().unwrap();
+        // empty tail before the closing tag of an indented parent
+        let mut tree = ETree::parse_str("<root>\n  <a/></root>");
+        assert_eq!(tree.append_next_tree(1, ETree::parse_str("<b/>")), Some(2));
+        assert_eq!(tree.append_child_xml(2, "<c/>"), Some(3));
+        tree.pretty("");
+        tree.write().unwrap();
+    }
+    #[test]
+    fn test_remove_subtree() {
+        let mut tree = ETree::parse_str("<root><a><b>x</b></a><c/></root>");
+        let pos = tree.find("/a").unwrap();
+        let cut = tree.remove_subtree(pos).unwrap();
+        assert_eq!(cut.find_all("//*").len(), 1);
+        assert_eq!(cut.node(0).unwrap().get_route(), "#");
+        assert_eq!(cut.find("/b"), Some(1));
+        assert_eq!(tree.find("/a"), None);
+        let pos = tree.find("/c").unwrap();
+        assert_eq!(tree.append_child_tree(pos, cut), Some(pos + 1));
+        assert!(tree.to_string().ends_with("<root><c><a><b>x</b></a></c></root>"));
+        assert!(tree.remove_subtree(100).is_none());
+    }
+    #[test]
+    fn test_insert_child_node() {
+        let mut tree = ETree::parse_str("<root>\n  <a/>\n  <b/>\n</root>");
+        let root = tree.root();
+        assert_eq!(tree.insert_child_node(root, 0, ETreeNode::new("first")), Some(1));
+        assert_eq!(tree.insert_child_node(root, 2, ETreeNode::new("middle")), Some(3));
+        assert_eq!(tree.insert_child_node(root, 100, ETreeNode::new("last")), Some(5));
+        assert_eq!(tree.insert_child_node(100, 0, ETreeNode::new("none")), None);
+        assert!(tree.to_string().ends_with("<root>\n  <first/>\n  <a/>\n  <middle/>\n  <b/>\n  <last/>\n</root>"));
+    }
+    #[test]
+    fn test_wrap_children() {
+        let mut tree = ETree::parse_str("<a><b>x</b><c/></a>");
+        tree.set_enable_index(true);
+        let pos = tree.wrap_children(tree.root(), ETreeNode::new("group")).unwrap();
+        assert_eq!(pos, 1);
+        assert!(tree.to_string().ends_with("<a><group><b>x</b><c/></group></a>"));
+        assert_eq!(tree.children(pos), vec![2, 3]);
+        assert_eq!(tree.parent(3), Some(pos));
+        assert_eq!(tree.find("/group/c"), Some(3));
+        let mut tree = ETree::parse_str("<a>\n  <b/>\n  <c/>\n</a>");
+        tree.wrap_children(tree.root(), ETreeNode::new("group"));
+        assert!(tree.to_string().ends_with("<a>\n  <group>\n    <b/>\n    <c/>\n  </group>\n</a>"));
+    }
+    #[test]
+    fn test_unwrap_node() {
+        let mut tree = ETree::parse_str("<p>A<n>B<c/>C</n>D</p>");
+        tree.set_enable_index(true);
+        assert!(!tree.unwrap_node(tree.root()));
+        assert!(tree.unwrap_node(tree.find("/n").unwrap()));
+        assert!(tree.to_string().ends_with("<p>AB<c/>CD</p>"));
+        assert_eq!(tree.parent(1), Some(0));
+        assert_eq!(tree.find("/c"), Some(1));
+        let mut tree = ETree::parse_str("<p><a/>x<n>y</n>z</p>");
+        assert!(tree.unwrap_node(tree.find("/n").unwrap()));
+        assert!(tree.to_string().ends_with("<p><a/>xyz</p>"));
+        let mut tree = ETree::parse_str("<a>\n  <group>\n    <b/>\n    <c/>\n  </group>\n</a>");
+        tree.unwrap_node(tree.find("/group").unwrap());
+        assert!(tree.to_string().ends_with("<a>\n  <b/>\n  <c/>\n</a>"));
+    }
+    #[test]
+    fn test_normalize_whitespace() {
+        let mut tree = ETree::parse_str("<a>\n  <b>\n    <c/>\n  </b>\n  <d/>\n</a>");
+        let pos = tree.find("//c").unwrap();
+        tree.remove(pos);
+        let pos = tree.find("/d").unwrap();
+        tree.append_child_node(pos, ETreeNode::new("e"));
+        tree.normalize_whitespace();
+        assert!(tree.to_string().ends_with("<a>\n  <b></b>\n  <d>\n    <e/>\n  </d>\n</a>\n"));
+    }
+    #[test]
+    fn test_preserve_space() {
+        let xml = "<doc><p>a</p><div><pre xml:space=\"preserve\">  x\n<b> y </b>\n</pre></div></doc>";
+        let mut tree = ETree::parse_str(xml);
+        tree.pretty("\n  ");
+        assert!(tree.to_string().ends_with("<doc>\n  <p>a</p>\n  <div>\n    <pre xml:space=\"preserve\">  x\n<b> y </b>\n</pre>\n  </div>\n</doc>\n"));
+        tree.noindent();
+        assert!(tree.to_string().ends_with("<doc><p>a</p><div><pre xml:space=\"preserve\">  x\n<b> y </b>\n</pre></div></doc>"));
+    }
+    #[test]
+    fn test_pretty_from() {
+        let mut tree = ETree::parse_str("<a><b/></a>");
+        tree.pretty_from("\n  ", 2);
+        let text = tree.to_string();
+        assert!(text.ends_with("<a>\n      <b/>\n    </a>\n    "));
+    }
+    #[test]
+    fn test_indent_unit() {
+        let tree = ETree::parse_str("<a>\n\t<b>\n\t\t<c/>\n\t</b>\n\t<!--x-->\n</a>");
+        assert_eq!(tree.indent_unit(), "\t");
+        let tree = ETree::parse_str("<a>\r\n  <b>x</b>\r\n</a>");
+        assert_eq!(tree.indent_unit(), "  ");
+        let tree = ETree::parse_str("<a>x<b>\n    <c/>\n  </b>\n</a>");
+        assert_eq!(tree.indent_unit(), "  ");
+        let tree = ETree::parse_str("<a><b/></a>");
+        assert_eq!(tree.indent_unit(), "");
+    }
+    #[test]
+    fn test_line_ending() {
+        let mut tree = ETree::parse_str("<a>\r\n  <b>x\ny</b>\r\n</a>");
+        assert_eq!(tree.line_ending(), "\r\n");
+        tree.set_line_ending("\n");
+        assert_eq!(tree.line_ending(), "\n");
+        assert!(tree.to_string().ends_with("<a>\n  <b>x\ny</b>\n</a>"));
+        tree.set_line_ending("\r\n");
+        assert!(tree.to_string().ends_with("<a>\r\n  <b>x\r\ny</b>\r\n</a>"));
+        tree.set_line_ending("\t");
+        assert_eq!(tree.line_ending(), "\r\n");
+    }
+    #[test]
+    fn test_links_after_edits() {
+        let mut tree = ETree::parse_str("<!--c--><r><a><x/><y/></a><b>t</b><c/></r>");
+        let check = |tree:&ETree| {
+            let mut rebuilt = tree.clone();
+            rebuilt.generate_links();
+            assert_eq!(tree.links, rebuilt.links);
+        };
+        let r = tree.root();
+        tree.append_child_node(r, ETreeNode::new("d"));
+        check(&tree);
+        tree.insert_child_node(r, 0, ETreeNode::new("e"));
+        check(&tree);
+        tree.append_next_node(tree.find("/a").unwrap(), ETreeNode::new("f"));
+        check(&tree);
+        tree.append_previous_tree(tree.find("/c").unwrap(), ETree::parse_str("<g><h/></g>"));
+        check(&tree);
+        tree.append_child_tree(tree.find("/a/x").unwrap(), ETree::parse_str("<i/>"));
+        check(&tree);
+        tree.wrap_children(tree.find("/a").unwrap(), ETreeNode::new("w"));
+        check(&tree);
+        tree.wrap_text_as_cdata(tree.find("/b").unwrap());
+        check(&tree);
+        tree.unwrap_node(tree.find("/a/w").unwrap());
+        check(&tree);
+        tree.unwrap_node(tree.find("/a/x/i").unwrap());
+        check(&tree);
+        tree.remove(tree.find("/a").unwrap());
+        check(&tree);
+        tree.remove(tree.find("/d").unwrap());
+        check(&tree);
+        assert_eq!(tree.write_with(&WriteOptions::new().declaration(false)).unwrap(), b"<!--c--><r><e/><f/><b><![CDATA[t]]></b><g><h/></g><c/></r>".to_vec());
+        tree.set_enable_index(false);
+        tree.append_child_node(r, ETreeNode::new("z"));
+        assert!(tree.links.is_empty());
+    }
+    #[test]
+    fn test_node_path() {
+        let tree = ETree::parse_str("<!--c--><root><a/><b><c/></b><a><c/></a></root>");
+        assert_eq!(tree.node_path(0), None);
+        assert_eq!(tree.node_path(1), Some("/root[1]".to_string()));
+        assert_eq!(tree.node_path(4), Some("/root[1]/b[1]/c[1]".to_string()));
+        assert_eq!(tree.node_path(6), Some("/root[1]/a[2]/c[1]".to_string()));
+        assert_eq!(tree.node_path(100), None);
+        let paths:Vec<Option<String>> = (0..tree.data.len()).map(|x| tree.node_path(x)).collect();
+        assert_eq!(paths, tree.node_paths());
+    }
+    #[test]
+    fn test_diff() {
+        let old = ETree::parse_str("<root><a k=\"1\">x</a><b><c/></b></root>");
+        let new = ETree::parse_str("<root>\n  <a k=\"2\" n=\"3\">y</a>\n  <d><e/></d>\n</root>");
+        assert_eq!(old.diff(&new), vec![
+            DiffOp::TextChanged("/root[1]/a[1]".to_string(), "x".to_string(), "y".to_string()),
+            DiffOp::AttrChanged("/root[1]/a[1]".to_string(), "k".to_string(), Some("1".to_string()), Some("2".to_string())),
+            DiffOp::AttrChanged("/root[1]/a[1]".to_string(), "n".to_string(), None, Some("3".to_string())),
+            DiffOp::Removed("/root[1]/b[1]".to_string()),
+            DiffOp::Added("/root[1]/d[1]".to_string()),
+        ]);
+        assert!(old.diff(&old.clone()).is_empty());
+    }
+    #[test]
+    fn test_validate() {
+        let tree = ETree::parse_str("<beans><bean id=\"a\"/><bean/><!--x--><bean/></beans>");
+        let errors = tree.validate(|t, pos| {
+            let node = t.node(pos).unwrap();
+            if node.get_name() == "bean" && node.get_attr("id").is_none() {
+                Some(format!("{} has no id", t.node_path(pos).unwrap()))
+            } else {
+                None
+            }
+        });
+        assert_eq!(errors, vec![(2, "/beans[1]/bean[2] has no id".to_string()), (4, "/beans[1]/bean[3] has no id".to_string())]);
+    }
+    #[test]
+    fn test_count_exists() {
+        let tree = ETree::parse_str("<root><a/><b><a/></b><a/></root>");
+        assert_eq!(tree.count("//a"), 3);
+        assert_eq!(tree.count("//c"), 0);
+        assert!(tree.exists("/b/a"));
+        assert!(!tree.exists("/c"));
+    }
+    #[test]
+    fn test_get_attr_mut() {
+        let mut tree = ETree::parse_str("<root><item count=\"1\"/><item count=\"5\"/><item/></root>");
+        for pos in tree.find_all("/item") {
+            if let Some(value) = tree.node_mut(pos).unwrap().get_attr_mut("count") {
+                *value = (value.parse::<i32>().unwrap() + 1).to_string();
+            }
+        }
+        assert!(tree.to_string().ends_with("<root><item count=\"2\"/><item count=\"6\"/><item/></root>"));
+    }
+    #[test]
+    fn test_text_ref() {
+        let mut tree = ETree::parse_str("<root><a>x</a><b/></root>");
+        assert_eq!(tree.node(1).unwrap().text_ref(), Some("x"));
+        assert_eq!(tree.node(2).unwrap().text_ref(), None);
+        assert_eq!(tree.node_mut(1).unwrap().take_text(), Some("x".to_string()));
+        assert_eq!(tree.node(1).unwrap().text_ref(), None);
+        assert!(tree.to_string().ends_with("<root><a/><b/></root>"));
+    }
+    #[test]
+    fn test_tail_ref() {
+        let tree = ETree::parse_str("<root><a/>x</root>");
+        assert_eq!(tree.node(1).unwrap().tail_ref(), "x");
+        assert_eq!(tree.node(1).unwrap().tail_ref(), tree.node(1).unwrap().get_tail());
+    }
+    #[test]
+    fn test_node_id() {
+        for enable_index in [false, true] {
+            let mut tree = ETree::parse_str("<root><a/><b/></root>");
+            tree.set_enable_index(enable_index);
+            let id = tree.id(tree.find("/b").unwrap()).unwrap();
+            tree.append_previous_node(1, ETreeNode::new("c"));
+            assert_eq!(tree.pos_of(id), Some(3));
+            assert_eq!(tree.node_by_id(id).unwrap().get_name(), "b");
+            tree.remove(3);
+            assert_eq!(tree.pos_of(id), None);
+            assert!(tree.node_by_id(id).is_none());
+        }
+        assert_eq!(ETree::parse_str("<root/>").id(5), None);
+    }
+    #[test]
+    fn test_append_id() {
+        let mut tree = ETree::from(ETreeNode::new("root"));
+        let root = tree.root();
+        let a = tree.append_child_node_id(root, ETreeNode::new("a")).unwrap();
+        let b = tree.append_previous_node_id(tree.pos_of(a).unwrap(), ETreeNode::new("b")).unwrap();
+        let c = tree.insert_child_node_id(root, 0, ETreeNode::new("c")).unwrap();
+        let d = tree.append_child_tree_id(tree.pos_of(a).unwrap(), ETree::from(ETreeNode::new("d"))).unwrap();
+        assert_eq!(tree.node_by_id(a).unwrap().get_name(), "a");
+        assert_eq!(tree.node_by_id(b).unwrap().get_name(), "b");
+        assert_eq!(tree.node_by_id(c).unwrap().get_name(), "c");
+        assert_eq!(tree.node_by_id(d).unwrap().get_name(), "d");
+        assert_eq!(tree.parent(tree.pos_of(d).unwrap()), tree.pos_of(a));
+        assert_eq!(tree.append_next_node_id(100, ETreeNode::new("e")), None);
+    }
+    #[test]
+    fn test_index_default() {
+        let mut tree = ETree::parse_str("<root><a/><b/></root>");
+        assert!(tree.get_enable_index());
+        assert!(ETree::from(ETreeNode::new("root")).get_enable_index());
+        assert_eq!(tree.pos(2), Some(2));
+        tree.remove(1);
+        assert_eq!(tree.pos(2), Some(1));
+        assert_eq!(tree.pos(1), None);
+        assert_eq!(tree.next(0), None);
+        assert_eq!(tree.parent(1), Some(0));
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let xml = "<?xml version=\"1.0\"?>\n<!--c--><root xmlns:x=\"urn:x\">\n  <x:a k=\"1\" j=\"2\">t</x:a>\n  <b><c/></b>\n</root>";
+        let tree = ETree::parse_str(xml);
+        let json = serde_json::to_string(&tree).unwrap();
+        let back:ETree = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_string(), tree.to_string());
+        assert_eq!(back.find("/b/c"), tree.find("/b/c"));
+        assert_eq!(back.parent(back.find("//c").unwrap()), back.find("/b"));
+        let node:ETreeNode = serde_json::from_str(&serde_json::to_string(tree.node(tree.find("/x:a").unwrap()).unwrap()).unwrap()).unwrap();
+        assert_eq!(node.get_attr_iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), vec!["k", "j"]);
+        assert_eq!(node.get_tag(), "{urn:x}a");
+    }
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_to_json() {
+        let tree = ETree::parse_str("<!--c--><config ver=\"2\">\n  <name>a</name>\n  <item id=\"1\"/><!--x-->\n  <item id=\"2\">t</item>\n</config>");
+        assert_eq!(tree.to_json(), serde_json::json!({
+            "config": {
+                "@ver": "2",
+                "name": {"#text": "a"},
+                "item": [{"@id": "1"}, {"@id": "2", "#text": "t"}],
+            }
+        }));
+    }
+    #[test]
+    fn test_from_flat() {
+        let mut text = ETreeNode::new("b");
+        text.set_text("x");
+        let comment = ETreeNode::comment("y");
+        let tree = ETree::from_flat(vec![
+            (0, ETreeNode::new("a")),
+            (1, text),
+            (2, ETreeNode::new("c")),
+            (1, comment),
+            (1, ETreeNode::new("d")),
+        ]);
+        assert!(tree.to_string().ends_with("<a><b>x<c/></b><!--y--><d/></a>"));
+        assert_eq!(tree.parent(4), Some(0));
+        assert_eq!(tree.find("/b/c"), Some(2));
+        assert_eq!(ETree::from_flat_checked(vec![(1, ETreeNode::new("a"))]).err(), Some(ETreeError::InvalidDepth { index:0, depth:1 }));
+        assert_eq!(ETree::from_flat_checked(vec![(0, ETreeNode::new("a")), (2, ETreeNode::new("b"))]).err(), Some(ETreeError::InvalidDepth { index:1, depth:2 }));
+        assert_eq!(ETree::from_flat_checked(vec![(0, ETreeNode::new("a")), (1, ETreeNode::new("<Comment>")), (2, ETreeNode::new("b"))]).err(), Some(ETreeError::InvalidDepth { index:2, depth:2 }));
+    }
+    #[test]
+    fn test_read_events() {
+        let mut events:Vec<String> = Vec::new();
+        let xml = "<?xml version=\"1.0\"?><!DOCTYPE r [<!ENTITY e \"v\">]><r xmlns:x=\"urn:x\"><x:a k=\"&lt;\">&e;</x:a><!--c--><b/><![CDATA[<d>]]><?pi x?></r>";
+        ETree::read_events(xml, |event| {
+            events.push(match event {
+                NodeEvent::Start(node) => format!("start {} {:?}", node.get_tag(), node.get_attr("k")),
+                NodeEvent::Empty(node) => format!("empty {}", node.get_name()),
+                NodeEvent::End(name) => format!("end {}", name),
+                NodeEvent::Text(text) => format!("text {}", text),
+                NodeEvent::Comment(text) => format!("comment {}", text),
+                NodeEvent::CData(text) => format!("cdata {}", text),
+                NodeEvent::PI(text) => format!("pi {}", text),
+                NodeEvent::DocType(_) => "doctype".to_string(),
+            });
+        }).unwrap();
+        assert_eq!(events, vec![
+            "doctype", "start {}r None", "start {urn:x}a Some(\"<\")", "text &e;", "end x:a",
+            "comment c", "empty b", "cdata <d>", "pi pi x", "end r",
+        ]);
+        assert!(ETree::read_events("<a></b>", |_| {}).is_err());
+    }
+    #[test]
+    fn test_evaluate() {
+        let tree = ETree::parse_str("<beans><bean id=\"a\">x<p>y</p>z<![CDATA[<w>]]><!--c--></bean><bean/><bean id=\"b\"/></beans>");
+        let root = tree.root();
+        assert_eq!(tree.evaluate("count(//bean)", root), XPathValue::Number(3.0));
+        assert_eq!(tree.evaluate("count(//none)", root), XPathValue::Number(0.0));
+        assert_eq!(tree.evaluate("boolean(/bean/p)", root), XPathValue::Boolean(true));
+        assert_eq!(tree.evaluate("string(//bean)", root), XPathValue::String("xyz<w>".to_string()));
+        assert_eq!(tree.evaluate("string(//bean/@id)", root), XPathValue::String("a".to_string()));
+        assert_eq!(tree.evaluate("string(//none)", root), XPathValue::String("".to_string()));
+        assert_eq!(tree.evaluate("//bean/@id", root), XPathValue::Strings(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(tree.evaluate("//bean", root), XPathValue::Nodes(vec![1, 5, 6]));
+        assert_eq!(tree.evaluate("count(p)", 1), XPathValue::Number(1.0));
+    }
+    #[test]
+    fn test_attr_step() {
+        let tree = ETree::parse_str("<beans><bean id=\"a\"><p id=\"c\"/></bean><bean/><bean id=\"b\">t</bean></beans>");
+        assert_eq!(tree.find_values("//bean/@id"), vec!["a", "b"]);
+        assert_eq!(tree.find_all("//bean/@id"), vec![1, 4]);
+        assert_eq!(tree.find_values("/bean//@id"), vec!["a", "c", "b"]);
+        assert_eq!(tree.find_values("/bean[@id='b']"), vec!["t"]);
+        assert_eq!(tree.find_all("@id"), vec![1, 2, 4]);
+        assert_eq!(tree.find_all("//bean[@id]"), vec![1, 4]);
+    }
+    #[test]
+    fn test_escape_text_attr() {
+        let special = "a < b > c & d \" e ' f";
+        let mut tree = ETree::from(ETreeNode::new("root"));
+        tree.node_mut(0).unwrap().set_attr("k", special);
+        tree.node_mut(0).unwrap().set_text(special);
+        tree.append_child_comment(0, " x < y & z ");
+        let output = tree.to_string();
+        assert!(output.ends_with("<root k=\"a &lt; b &gt; c &amp; d &quot; e &apos; f\">a &lt; b &gt; c &amp; d &quot; e &apos; f<!-- x < y & z --></root>"));
+        let back = ETree::parse_str(&output);
+        assert_eq!(back.node(0).unwrap().get_attr("k").as_deref(), Some(special));
+        assert_eq!(back.node(0).unwrap().get_text().as_deref(), Some(special));
+        assert_eq!(back.node(1).unwrap().get_text().as_deref(), Some(" x < y & z "));
+    }
+    #[test]
+    fn test_roundtrip_stable() {
+        let xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE r [<!ENTITY e \"v\">]>\n<r title=\"Tom &amp; Jerry\" q='&quot;x&apos;' k=\"&amp;e; &e;\">\n  <a>&lt;b&gt; &amp;amp; &e; &amp;e; &#65;</a>\n  <!-- &amp; < -->\n  <![CDATA[&amp; <c>]]>\n  <?pi a&amp;b?>\n</r>\n";
+        let tree = ETree::parse_str(xml);
+        assert_eq!(tree.node(1).unwrap().get_attr("title").as_deref(), Some("Tom & Jerry"));
+        let first = tree.to_string();
+        let second = ETree::parse_str(&first).to_string();
+        assert_eq!(first, second);
+        assert!(first.contains("title=\"Tom &amp; Jerry\""));
+        assert!(first.contains("<a>&lt;b&gt; &amp;amp; &e; &amp;e; A</a>"));
+        // a literal `&e;` is escaped again while the reference to the declared entity is kept
+        assert!(first.contains("k=\"&amp;e; &e;\""));
+        assert_eq!(tree.node(2).unwrap().get_text().as_deref(), Some("<b> &amp; &e; &e; A"));
+    }
+    #[test]
+    fn test_parse_str_limited() {
+        let content = "<a><b><c/></b><d/></a>";
+        assert!(ETree::parse_str_limited(content, 3, 4).is_ok());
+        match ETree::parse_str_limited(content, 2, 4) {
+            Err(ETreeError::TooDeep { max_depth, .. }) => assert_eq!(max_depth, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match ETree::parse_str_limited(content, 3, 3) {
+            Err(ETreeError::TooManyNodes { max_nodes, .. }) => assert_eq!(max_nodes, 3),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let deep = format!("{}{}", "<x>".repeat(1000), "</x>".repeat(1000));
+        assert!(matches!(ETree::parse_str_limited(&deep, 64, usize::MAX), Err(ETreeError::TooDeep { .. })));
+    }
+    #[test]
+    fn test_mismatched_tag() {
+        let content = "<root>\n<a></b></root>";
+        match ETree::parse_str_checked(content) {
+            Err(ETreeError::MismatchedTag { expected, found, position }) => {
+                assert_eq!(expected, "a");
+                assert_eq!(found, "b");
+                assert!(content[position..].starts_with("b>"));
+            },
+            other => panic!("unexpected result {:?}", other),
+        }
+        match ETree::parse_str_checked("<root><a></a>") {
+            Err(ETreeError::UnclosedTag { name, .. }) => assert_eq!(name, "root"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(ETree::parse_str_checked("<x:root xmlns:x=\"urn:x\"><a/></x:root>").is_ok());
+    }
+    #[test]
+    fn test_deep_route() {
+        let mut content = String::new();
+        for level in 0..20 {
+            content.push_str(&format!("<l{}>", level));
+            for _ in 0..250 {
+                content.push_str("<leaf/>");
+            }
+        }
+        for level in (0..20).rev() {
+            content.push_str(&format!("</l{}>", level));
+        }
+        for enable_index in [true, false] {
+            let mut tree = ETree::parse_str(&content);
+            tree.set_enable_index(enable_index);
+            assert_eq!(tree.descendant(tree.root()).len(), 20 * 251 - 1);
+            let deepest = tree.find("//l19").unwrap();
+            assert!(tree.node(deepest).unwrap().get_idx() > 4000);
+            assert_eq!(tree.children(deepest).len(), 250);
+            let leaf = *tree.children(deepest).last().unwrap();
+            assert!(tree.node(leaf).unwrap().get_idx() > 5000);
+            assert_eq!(tree.parent(leaf), Some(deepest));
+            let mut pos = deepest;
+            for level in (0..19).rev() {
+                pos = tree.parent(pos).unwrap();
+                assert_eq!(tree.node(pos).unwrap().get_localname(), format!("l{}", level));
+            }
+            assert_eq!(tree.parent(pos), None);
+            assert!(tree.to_string().ends_with(&content));
+        }
+    }
+    #[test]
+    fn test_children_elements() {
+        let tree = ETree::parse_str("<root><!--c--><a/><![CDATA[x]]><?pi?><b><!--d--></b></root>");
+        let root = tree.root();
+        assert_eq!(tree.children(root).len(), 5);
+        let elements = tree.children_elements(root);
+        assert_eq!(elements.iter().map(|&i| tree.node(i).unwrap().get_localname()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(tree.children_elements(elements[1]).is_empty());
+        assert!(tree.node(tree.children(root)[0]).unwrap().is_special());
+        assert!(!tree.node(root).unwrap().is_special());
+    }
+    #[test]
+    fn test_append_child_comment() {
+        let mut tree = ETree::parse_str("<root><a/></root>");
+        let pos = tree.append_child_comment(0, " generated ").unwrap();
+        assert!(tree.node(pos).unwrap().is_comment());
+        assert!(!tree.node(1).unwrap().is_comment());
+        assert_eq!(tree.node(pos).unwrap().get_text().as_deref(), Some(" generated "));
+        assert!(tree.to_string().ends_with("<root><a/><!-- generated --></root>"));
+        assert_eq!(tree.append_child_comment(9, "x"), None);
+    }
+    #[test]
+    fn test_siblings() {
+        let mut tree = ETree::parse_str("<ul><li>1</li><li>2<b/></li><marker/><li>3</li><!--x--><li>4</li></ul>");
+        for enable_index in [true, false] {
+            tree.set_enable_index(enable_index);
+            let marker = tree.find("//marker").unwrap();
+            let after = tree.following_siblings(marker);
+            assert_eq!(after.len(), 3);
+            assert_eq!(after.iter().filter(|&&i| tree.node(i).unwrap().get_localname() == "li").map(|&i| tree.node(i).unwrap().get_text().unwrap()).collect::<Vec<_>>(), vec!["3", "4"]);
+            let before = tree.preceding_siblings(marker);
+            assert_eq!(before.iter().map(|&i| tree.node(i).unwrap().get_text().unwrap()).collect::<Vec<_>>(), vec!["1", "2"]);
+            assert!(tree.following_siblings(tree.root()).is_empty());
+            assert!(tree.preceding_siblings(99).is_empty());
+        }
+    }
+    #[test]
+    fn test_sibling_index() {
+        let tree = ETree::parse_str("<ul><li>1</li><li>2<b/></li><li>3</li></ul>");
+        let items = tree.children(tree.root());
+        assert_eq!(items.iter().map(|&i| tree.sibling_index(i)).collect::<Vec<_>>(), vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(tree.sibling_count(items[1]), Some(3));
+        let b = tree.find("//b").unwrap();
+        assert_eq!(tree.sibling_index(b), Some(0));
+        assert_eq!(tree.sibling_count(b), Some(1));
+        assert_eq!(tree.sibling_index(tree.root()), None);
+        assert_eq!(tree.sibling_count(tree.root()), None);
+    }
+    #[test]
+    fn test_root_node() {
+        let tree = ETree::parse_str("<?xml version=\"1.0\"?>\n<!DOCTYPE root>\n<!--a-->\n<!--b-->\n<?pi x?>\n<root><c/></root>\n<!--after-->");
+        assert_eq!(tree.root(), 4);
+        assert_eq!(tree.root_node().unwrap().get_localname(), "root");
+        assert_eq!(tree.find("/c"), Some(5));
+        let mut empty = ETree::parse_str("<!--only-->");
+        assert_eq!(empty.root(), 1);
+        assert!(empty.root_node().is_none());
+        empty.remove(0);
+        assert!(empty.root_node().is_none());
+    }
+    #[test]
+    fn test_empty_predicate() {
+        let tree = ETree::parse_str("<root><i a=\"\">1</i><i a=\"x\"></i><i/><i b=\"y\">4</i></root>");
+        let names = |path:&str| tree.find_all(path);
+        assert_eq!(names("/i[@a='']"), vec![1]);
+        assert_eq!(names("/i[text()='']"), vec![2, 3]);
+        assert_eq!(names("/i[not(@a)]"), vec![3, 4]);
+        assert_eq!(names("/i[not(@a) and text()='']"), vec![3]);
+        assert_eq!(names("/i[not(@a='x' or text()='1')]"), vec![3, 4]);
+        assert_eq!(names("/i[@*]"), vec![1, 2, 4]);
+        assert_eq!(names("/i[not(@*)]"), vec![3]);
+    }
+    #[test]
+    fn test_pretty_attrs() {
+        let tree = ETree::parse_str("<beans a=\"1\" b=\"2\" c=\"3\"><bean id=\"x\" class=\"y\"/><bean id=\"a-very-long-identifier\"/></beans>");
+        let output = String::from_utf8(tree.write_with(&WriteOptions::new().indent("\n  ").wrap_attrs(2, 30)).unwrap()).unwrap();
+        assert!(output.ends_with("<beans\n  a=\"1\"\n  b=\"2\"\n  c=\"3\">\n  <bean id=\"x\" class=\"y\"/>\n  <bean\n    id=\"a-very-long-identifier\"/>\n</beans>\n"));
+        let back = ETree::parse_str(&output);
+        assert_eq!(back.node(0).unwrap().get_attr("c").as_deref(), Some("3"));
+        assert_eq!(back.node(2).unwrap().get_attr("id").as_deref(), Some("a-very-long-identifier"));
+        // formatting is not kept in the tree
+        assert_eq!(tree.to_string(), "<?xml version=\"\"?>\n<beans a=\"1\" b=\"2\" c=\"3\"><bean id=\"x\" class=\"y\"/><bean id=\"a-very-long-identifier\"/></beans>");
+    }
+    #[test]
+    fn test_pretty_capped() {
+        let mut tree = ETree::parse_str("<a><b><c><d><e/></d></c></b><f/></a>");
+        tree.pretty_capped("\n  ", 2);
+        assert!(tree.to_string().ends_with("<a>\n  <b>\n    <c>\n    <d>\n    <e/>\n    </d>\n    </c>\n  </b>\n  <f/>\n</a>\n"));
+        tree.normalize_whitespace();
+        assert!(tree.to_string().contains("\n        <e/>"));
+        let output = String::from_utf8(tree.write_with(&WriteOptions::new().indent("\n  ").indent_cap(2)).unwrap()).unwrap();
+        assert!(output.ends_with("<a>\n  <b>\n    <c>\n    <d>\n    <e/>\n    </d>\n    </c>\n  </b>\n  <f/>\n</a>\n"));
+        assert!(tree.to_string().contains("\n        <e/>"));
+    }
+    #[test]
+    fn test_parse_str_trimmed() {
+        let content = "<root>\n  <a>\n    <b> x </b>\n  </a>\n  <p>some <i>mixed</i> text</p>\n  <p>\n    <i>x</i> <i>y</i>\n  </p>\n  <pre xml:space=\"preserve\">\n    <c/>\n  </pre>\n</root>\n";
+        let tree = ETree::parse_str_trimmed(content).unwrap();
+        assert!(tree.to_string().ends_with("<root><a><b> x </b></a><p>some <i>mixed</i> text</p><p><i>x</i><i>y</i></p><pre xml:space=\"preserve\">\n    <c/>\n  </pre></root>"));
+        assert_eq!(tree.indent_unit(), "");
+        assert!(ETree::parse_str_trimmed("<a></b>").is_err());
+    }
+    #[test]
+    fn test_is_mixed_content() {
+        let tree = ETree::parse_str("<root>\n  <p>some <i>mixed</i></p>\n  <p><i>x</i> tail</p>\n  <p>\n    <i>x</i>\n  </p>\n  <p>text<!--c--></p>\n</root>");
+        let p = tree.children_elements(tree.root());
+        assert_eq!(p.iter().map(|&x| tree.is_mixed_content(x)).collect::<Vec<_>>(), vec![true, true, false, false]);
+        assert!(!tree.is_mixed_content(tree.root()));
+        assert!(!tree.is_mixed_content(99));
+    }
+    #[test]
+    fn test_append_child_xml() {
+        let mut tree = ETree::parse_str("<root><a/></root>");
+        let pos = tree.append_child_xml(0, "<b k=\"v\"><c>x</c></b>").unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_attr("k").as_deref(), Some("v"));
+        assert_eq!(tree.find("/b/c"), Some(pos + 1));
+        assert!(tree.to_string().ends_with("<root><a/><b k=\"v\"><c>x</c></b></root>"));
+        assert_eq!(tree.append_child_xml(0, "<b></c>"), None);
+        assert_eq!(tree.append_child_xml(99, "<b/>"), None);
+    }
+    #[test]
+    fn test_parse_fragment() {
+        let fragment = ETree::parse_fragment("<a>1</a> <b/><!--c--><d/>").unwrap();
+        assert_eq!(fragment.root(), 0);
+        assert_eq!(fragment.next(0), Some(1));
+        assert_eq!(fragment.following_siblings(0), vec![1, 2, 3]);
+        let mut tree = ETree::parse_str("<root><x/><y/></root>");
+        let pos = tree.append_next_tree(1, fragment.clone()).unwrap();
+        assert_eq!(pos, 2);
+        assert!(tree.to_string().ends_with("<root><x/><a>1</a> <b/><!--c--><d/><y/></root>"));
+        assert_eq!(tree.children(0).len(), 6);
+        let mut tree = ETree::parse_str("<root>\n  <x/>\n</root>");
+        tree.append_child_tree(0, fragment);
+        assert!(tree.to_string().ends_with("<root>\n  <x/>\n  <a>1</a>\n  <b/>\n  <!--c-->\n  <d/>\n</root>"));
+        let pos = tree.append_child_xml(1, "<p/><q/>").unwrap();
+        assert_eq!(tree.children(1), vec![pos, pos + 1]);
+        assert!(ETree::parse_fragment("<a></b>").is_err());
+        // unlike a document, a fragment can't have a declaration
+        assert!(ETree::parse_str_checked("<?xml version=\"1.0\"?><a/>").is_ok());
+        match ETree::parse_fragment("<a/> <?xml version=\"1.0\"?><b/>") {
+            Err(ETreeError::Xml { position, .. }) => assert_eq!(position, 5),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let fragment = ETree::parse_fragment("<!--c-->").unwrap();
+        assert_eq!(fragment.root_node(), None);
+        assert_eq!(fragment.node(0).map(|x| x.is_comment()), Some(true));
+        assert!(ETree::parse_fragment("").unwrap().root_node().is_none());
+    }
+    #[test]
+    fn test_append_after_compact_close() {
+        let mut tree = ETree::parse_str("<r>\n  <a/></r>");
+        let a = tree.find("/a").unwrap();
+        assert_eq!(tree.append_next_tree(a, ETree::parse_str("<b/>")), Some(a + 1));
+        assert!(tree.to_string().ends_with("<r>\n  <a/>\n  <b/></r>"));
+        let r = tree.root();
+        tree.append_child_xml(r, "<c/><d/>").unwrap();
+        assert!(tree.to_string().ends_with("<r>\n  <a/>\n  <b/>\n  <c/>\n  <d/></r>"));
+    }
+    #[test]
+    fn test_detach_node() {
+        let mut tree = ETree::parse_str("<root><a k=\"v\">x<b/></a></root>");
+        let node = tree.detach_node(1).unwrap();
+        assert_eq!(node.get_idx(), 0);
+        assert_eq!(node.get_route(), "");
+        assert_eq!(node.get_attr("k").as_deref(), Some("v"));
+        let pos = tree.append_child_node(0, node).unwrap();
+        assert_eq!(tree.parent(pos), Some(0));
+        assert!(tree.to_string().ends_with("<root><a k=\"v\">x<b/></a><a k=\"v\">x</a></root>"));
+        assert!(tree.detach_node(99).is_none());
+    }
+    #[test]
+    fn test_compact() {
+        let mut tree = ETree::parse_str("<root><a><b/></a><c><d/></c></root>");
+        for _ in 0..5 {
+            let pos = tree.append_child_node(1, ETreeNode::new("x")).unwrap();
+            tree.remove(pos);
+        }
+        tree.append_child_node(4, ETreeNode::new("e"));
+        tree.remove(1);
+        let output = tree.to_string();
+        assert!(tree.node(3).unwrap().get_idx() > 5);
+        tree.compact();
+        assert_eq!(tree.to_string(), output);
+        for pos in 0..4 {
+            assert_eq!(tree.node(pos).unwrap().get_idx(), pos);
+            assert_eq!(tree.pos(pos), Some(pos));
+        }
+        assert_eq!(tree.node(3).unwrap().get_route(), "#0#1#2#");
+        assert_eq!(tree.parent(3), Some(2));
+        let pos = tree.append_child_node(0, ETreeNode::new("f")).unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_idx(), 4);
+    }
+    #[test]
+    fn test_find_local_iter() {
+        let tree = ETree::parse_str("<root xmlns:ns=\"urn:x\"><ns:item><ns:name>a</ns:name></ns:item><item><name>b</name></item><other/></root>");
+        assert_eq!(tree.find_iter("//item").count(), 1);
+        assert_eq!(tree.find_local_iter("//item").collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(tree.find_local_iter("/ns:item").count(), 2);
+        assert_eq!(tree.find_local_iter("//item[name='a']").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_local_iter("//item/name").count(), 2);
+    }
+    #[test]
+    fn test_find_ns_iter() {
+        let mut with_ns = ETree::parse_str("<root xmlns=\"urn:a\"><foo/><b:foo xmlns:b=\"urn:b\"/></root>");
+        let mut without_ns = ETree::parse_str("<root><foo/></root>");
+        assert_eq!(with_ns.find_iter("//foo").count(), 1);
+        assert_eq!(without_ns.find_iter("//foo").count(), 1);
+        for tree in [&mut with_ns, &mut without_ns] {
+            tree.register_namespace("a", "urn:a");
+            tree.register_namespace("x", "urn:b");
+        }
+        assert_eq!(with_ns.find_ns_iter("//a:foo").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(with_ns.find_ns_iter("//x:foo").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(with_ns.find_ns_iter("//foo").count(), 0);
+        assert_eq!(with_ns.find_ns_iter("//y:foo").count(), 0);
+        assert_eq!(without_ns.find_ns_iter("//a:foo").count(), 0);
+        assert_eq!(without_ns.find_ns_iter("//foo").collect::<Vec<_>>(), vec![1]);
+    }
+    #[test]
+    fn test_get_full_name() {
+        let mut tree = ETree::parse_str("<root xmlns:p=\"urn:p\"><p:a/></root>");
+        tree.register_namespace("q", "urn:q");
+        let mut node = ETreeNode::new("b");
+        node.set_namespace("urn:q");
+        let b = tree.append_child_node(0, node).unwrap();
+        let mut node = ETreeNode::new("c");
+        node.set_namespace("urn:q");
+        let c = tree.append_child_node(b, node).unwrap();
+        let mut node = ETreeNode::new("d");
+        node.set_namespace("urn:p");
+        tree.append_child_node(0, node);
+        tree.register_namespace("p", "urn:p");
+        let mut node = ETreeNode::new("e");
+        node.set_namespace("urn:e");
+        tree.append_child_node(0, node);
+        assert_eq!(tree.get_full_name(b).as_deref(), Some("q:b"));
+        assert_eq!(tree.get_full_name(c).as_deref(), Some("q:c"));
+        let output = tree.to_string();
+        assert!(output.ends_with("<root xmlns:p=\"urn:p\"><p:a/><q:b xmlns:q=\"urn:q\"><q:c/></q:b><p:d/><e xmlns=\"urn:e\"/></root>"));
+        let back = ETree::parse_str(&output);
+        let names:Vec<(String, String)> = back.descendant(0).iter().map(|&x| (back.node(x).unwrap().get_localname(), back.node(x).unwrap().get_namespace())).collect();
+        assert_eq!(names, vec![
+            ("a".to_string(), "urn:p".to_string()),
+            ("b".to_string(), "urn:q".to_string()),
+            ("c".to_string(), "urn:q".to_string()),
+            ("d".to_string(), "urn:p".to_string()),
+            ("e".to_string(), "urn:e".to_string()),
+        ]);
+        let tree = ETree::parse_str("<root xmlns=\"urn:a\"><a/></root>");
+        assert!(tree.to_string().ends_with("<root xmlns=\"urn:a\"><a/></root>"));
+    }
+    #[test]
+    fn test_text_of() {
+        let tree = ETree::parse_str("<doc><p>first <b>bold</b> end</p><!--x--><p>second<![CDATA[ <raw>]]></p><q>no</q></doc>");
+        assert_eq!(tree.inner_text(1).as_deref(), Some("first bold end"));
+        assert_eq!(tree.inner_text(99), None);
+        assert_eq!(tree.text_of("//p", "\n"), "first bold end\nsecond <raw>");
+        assert_eq!(tree.text_of("//none", "\n"), "");
+    }
+    #[test]
+    fn test_node_hash() {
+        use std::collections::HashSet;
+        let tree = ETree::parse_str("<root><a x=\"1\" y=\"2\">t</a><a y=\"2\" x=\"1\">t</a><p:a xmlns:p=\"urn:p\" x=\"1\" y=\"2\">t</p:a><a x=\"1\">t</a><a x=\"1\" y=\"2\">u</a></root>");
+        let nodes:Vec<&ETreeNode> = tree.children(0).into_iter().map(|x| tree.node(x).unwrap()).collect();
+        assert_eq!(nodes[0], nodes[1]);
+        assert_ne!(nodes[0].get_idx(), nodes[1].get_idx());
+        assert_ne!(nodes[0], nodes[2]);
+        assert_ne!(nodes[0], nodes[3]);
+        assert_ne!(nodes[0], nodes[4]);
+        let unique:HashSet<&ETreeNode> = nodes.iter().copied().collect();
+        assert_eq!(unique.len(), 4);
+        assert!(unique.contains(&tree.detach_node(2).unwrap()));
+    }
+    #[test]
+    fn test_node_eq_ordered() {
+        let tree = ETree::parse_str("<root><a x=\"1\" y=\"2\"/><a y=\"2\" x=\"1\"/><a x=\"1\" y=\"2\"/></root>");
+        let (a, b, c) = (tree.node(1).unwrap(), tree.node(2).unwrap(), tree.node(3).unwrap());
+        assert!(a.eq_unordered(b));
+        assert!(!a.eq_ordered(b));
+        assert!(a.eq_ordered(c));
+        assert_eq!(a == b, a.eq_unordered(b));
+    }
+    #[test]
+    fn test_write_with() {
+        let tree = ETree::parse_str("<?xml version=\"1.0\"?>\n<root b=\"2\" a=\"it's\"><x/><y>t</y></root>");
+        let write = |opts:WriteOptions| String::from_utf8(tree.write_with(&opts).unwrap()).unwrap();
+        assert_eq!(write(WriteOptions::new()), tree.to_string());
+        assert_eq!(write(WriteOptions::new().declaration(false)), "<root b=\"2\" a=\"it&apos;s\"><x/><y>t</y></root>");
+        assert_eq!(write(WriteOptions::new().declaration(false).self_close(false).sort_attrs(true).single_quote(true)), "<root a='it&apos;s' b='2'><x></x><y>t</y></root>");
+        assert_eq!(write(WriteOptions::new().indent("\n  ").line_ending("\r\n")), "<?xml version=\"1.0\"?>\r\n<root b=\"2\" a=\"it&apos;s\">\r\n  <x/>\r\n  <y>t</y>\r\n</root>\r\n");
+        assert_eq!(tree.indent_unit(), "");
+        assert!(tree.to_string().ends_with("<root b=\"2\" a=\"it&apos;s\"><x/><y>t</y></root>"));
+    }
+    #[test]
+    fn test_parse_with() {
+        let content = "<?xml version=\"1.0\" encoding=\"Shift_JIS\"?>\n<!DOCTYPE r [<!ENTITY e \"v\">]>\n<r>\n  <a>&e;</a>\n</r>";
+        let tree = ETree::parse_with(content, &ReadOptions::default()).unwrap();
+        assert_eq!(tree.to_string(), ETree::parse_str(content).to_string());
+        let tree = ETree::parse_with(content, &ReadOptions::new().trim(true).resolve_entities(true).encoding("UTF-8")).unwrap();
+        assert!(tree.to_string().ends_with("<r><a>v</a></r>"));
+        assert_eq!(tree.get_encoding().as_deref(), Some("UTF-8"));
+        assert!(matches!(ETree::parse_with(content, &ReadOptions::new().max_depth(1)), Err(ETreeError::TooDeep { .. })));
+        assert!(matches!(ETree::parse_with(content, &ReadOptions::new().max_nodes(2)), Err(ETreeError::TooManyNodes { .. })));
+    }
+    #[test]
+    fn test_parse_trim_text() {
+        let content = "<r>\n  <a x=\"1\">\n    v\n  </a>\n  <b> w <c/> </b>\n</r>";
+        let tree = ETree::parse_with(content, &ReadOptions::new().trim_text(true)).unwrap();
+        assert!(tree.to_string().ends_with("<r><a x=\"1\">v</a><b>w<c/></b></r>"));
+        assert_eq!(tree.find("//a[text()='v']"), tree.find("//a"));
+        let tree = ETree::parse_with(content, &ReadOptions::default()).unwrap();
+        assert_eq!(tree.find("//a[text()='v']"), None);
+    }
+    #[test]
+    fn test_attr_order() {
+        let tree = ETree::parse_str_checked("<a z=\"1\" xmlns:b=\"u2\" xmlns:a=\"u1\" m=\"2\" b:k=\"3\" a:k=\"4\"/>").unwrap();
+        let keys:Vec<&str> = tree.node(0).unwrap().get_attr_iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["z", "xmlns:b", "xmlns:a", "m", "b:k", "a:k"]);
+        assert!(tree.to_string().ends_with("<a z=\"1\" xmlns:b=\"u2\" xmlns:a=\"u1\" m=\"2\" b:k=\"3\" a:k=\"4\"/>"));
+        assert!(matches!(ETree::parse_str_checked("<a x=\"1\" x=\"2\" y=\"3\"/>"), Err(ETreeError::Xml { .. })));
+    }
+    #[test]
+    fn test_has_declaration() {
+        let tree = ETree::parse_str("<?xml version=\"1.0\"?><a/>");
+        assert!(tree.has_declaration());
+        assert!(tree.subtree(0).has_declaration());
+        let mut tree = ETree::parse_str("<a/>");
+        assert!(!tree.has_declaration());
+        assert_eq!(tree.write_with(&WriteOptions::new().declaration(tree.has_declaration())).unwrap(), b"<a/>");
+        tree.set_version("1.1");
+        assert!(tree.has_declaration());
+        let mut tree = ETree::from(ETreeNode::new("a"));
+        assert!(!tree.has_declaration());
+        assert_eq!(tree.get_version().as_deref(), Some("1.0"));
+        tree.set_encoding("UTF-8");
+        assert!(tree.has_declaration());
+    }
+    #[test]
+    fn test_resolve_base() {
+        let tree = ETree::parse_str("<r xml:base=\"http://h.org/a/b/doc.xml?q#f\"><s xml:base=\"../c/\"><t xml:base=\"d.xml\"/><u/></s><v xml:base=\"/x/./y/../z\"/><w xml:base=\"urn:w\"/></r>");
+        let base = |path:&str| tree.resolve_base(tree.find(path).unwrap());
+        assert_eq!(tree.resolve_base(tree.root()).as_deref(), Some("http://h.org/a/b/doc.xml?q#f"));
+        assert_eq!(base("//s").as_deref(), Some("http://h.org/a/c/"));
+        assert_eq!(base("//t").as_deref(), Some("http://h.org/a/c/d.xml"));
+        assert_eq!(base("//u").as_deref(), Some("http://h.org/a/c/"));
+        assert_eq!(base("//v").as_deref(), Some("http://h.org/x/z"));
+        assert_eq!(base("//w").as_deref(), Some("urn:w"));
+        let tree = ETree::parse_str("<a><b/></a>");
+        assert_eq!(tree.resolve_base(tree.find("/b").unwrap()), None);
+        assert_eq!(join_uri("http://h.org/a/b", "#g"), "http://h.org/a/b#g");
+        assert_eq!(join_uri("http://h.org/a/b?q", "?p"), "http://h.org/a/b?p");
+        assert_eq!(join_uri("http://h.org/a/b", "//g.org/x"), "http://g.org/x");
+        assert_eq!(join_uri("http://h.org", "x/../y"), "http://h.org/y");
+        assert_eq!(join_uri("http://h.org/a/b/", "../.."), "http://h.org/");
+        assert_eq!(join_uri("a/b", "c"), "a/c");
+        assert_eq!(join_uri("a/b", "../../c"), "c");
+    }
+    #[test]
+    fn test_find_absolute() {
+        let tree = ETree::parse_str("<!--c--><root><a><b/><root><a/></root></a><b/></root>");
+        let a = tree.find("/a").unwrap();
+        assert_eq!(tree.find_absolute("/a"), None);
+        assert_eq!(tree.find_absolute("/root"), Some(tree.root()));
+        assert_eq!(tree.find_absolute("/root/a"), Some(a));
+        assert_eq!(tree.find_absolute_iter("//root/a").count(), 2);
+        assert_eq!(tree.find_absolute_iter("//b").count(), 2);
+        assert_eq!(tree.find_at("/b", a), tree.find("/a/b"));
+        assert_eq!(tree.find_absolute("/root/b"), Some(tree.children(tree.root())[1]));
+    }
+    #[test]
+    fn test_source_offset() {
+        let content = "<?xml version=\"1.0\"?>\n<root>\n  <!--c--><a x=\"1\">t</a>\n  <b/>\n\t<é/><é/></root>";
+        for opts in [ReadOptions::default(), ReadOptions::new().trim_text(true)] {
+            let tree = ETree::parse_with(content, &opts).unwrap();
+            let offsets:Vec<Option<usize>> = tree.clone().into_iter().map(|x| x.get_source_offset()).collect();
+            assert_eq!(offsets, vec![Some(22), None, Some(39), Some(56), Some(62), Some(67)]);
+            let node = tree.node(tree.find("/b").unwrap()).unwrap();
+            assert_eq!(node.get_source_line_column(content), Some((4, 3)));
+            let node = tree.node(*tree.children(tree.root()).last().unwrap()).unwrap();
+            assert_eq!(node.get_source_line_column(content), Some((5, 6)));
+        }
+        assert_eq!(ETreeNode::new("a").get_source_offset(), None);
+        assert_eq!(ETreeNode::new("a").get_source_line_column(content), None);
+    }
+    #[test]
+    fn test_parse_many() {
+        let content = "<?xml version=\"1.0\"?>\n<r n=\"1\"><a/></r>\n<!--c1-->\n<?xml version=\"1.1\"?><r n=\"2\"/><!--c2--><r n=\"3\">t</r>\n";
+        let trees = ETree::parse_many(content).unwrap();
+        assert_eq!(trees.len(), 3);
+        let n:Vec<String> = trees.iter().map(|x| x.node(x.root()).unwrap().get_attr("n").unwrap()).collect();
+        assert_eq!(n, vec!["1", "2", "3"]);
+        assert_eq!(trees[0].get_version().as_deref(), Some("1.0"));
+        assert_eq!(trees[0].to_string(), "<?xml version=\"1.0\"?>\n<r n=\"1\"><a/></r>\n<!--c1-->\n");
+        assert_eq!(trees[1].get_version().as_deref(), Some("1.1"));
+        assert!(!trees[2].has_declaration());
+        assert!(trees[2].to_string().ends_with("<!--c2--><r n=\"3\">t</r>\n"));
+        assert_eq!(ETree::parse_many(" \n").unwrap().len(), 0);
+        let content = "<r/><r><a></b></r>";
+        match ETree::parse_many(content) {
+            Err(ETreeError::MismatchedTag { position, .. }) => assert!(content[position..].starts_with("b>")),
+            other => panic!("{:?}", other),
+        }
+    }
+    #[test]
+    fn test_collapse_empty() {
+        let mut tree = ETree::parse_str("<r>\n  <a>\n  </a>\n  <b> x </b>\n  <c><!--k--></c>\n  <d xml:space=\"preserve\"> <e> </e></d>\n  <f></f>\n</r>");
+        tree.collapse_empty();
+        assert!(tree.to_string().ends_with("<r>\n  <a/>\n  <b> x </b>\n  <c><!--k--></c>\n  <d xml:space=\"preserve\"> <e> </e></d>\n  <f/>\n</r>"));
+        assert_eq!(tree.node(tree.find("/a").unwrap()).unwrap().get_text(), None);
+    }
+    #[test]
+    fn test_collect_attrs() {
+        let tree = ETree::parse_str("<r><a src=\"/etc/x\" n=\"1\"/><b><a log=\"/var/y\"/></b><a/></r>");
+        let found = tree.collect_attrs("//a");
+        let a = tree.find_all("//a");
+        assert_eq!(found, vec![
+            (a[0], "src".to_string(), "/etc/x".to_string()),
+            (a[0], "n".to_string(), "1".to_string()),
+            (a[1], "log".to_string(), "/var/y".to_string()),
+        ]);
+        let paths:Vec<String> = tree.collect_attrs("//*").into_iter().filter(|x| x.2.starts_with('/')).map(|x| x.1).collect();
+        assert_eq!(paths, vec!["src", "log"]);
+        assert!(tree.collect_attrs("//none").is_empty());
+    }
+    #[test]
+    fn test_get_attr_ns() {
+        let xlink = "http://www.w3.org/1999/xlink";
+        let tree = ETree::parse_str("<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xl=\"http://www.w3.org/1999/xlink\"><use xl:href=\"#a\" href=\"#b\"/><g xmlns:l=\"http://www.w3.org/1999/xlink\"><use l:href=\"#c\" xml:lang=\"en\"/></g></svg>");
+        let uses = tree.find_all("//use");
+        let attr = |pos:usize, uri:&str, local:&str| tree.node(pos).unwrap().get_attr_ns(uri, local, &tree.in_scope_namespaces(pos));
+        assert_eq!(attr(uses[0], xlink, "href").as_deref(), Some("#a"));
+        assert_eq!(attr(uses[0], "", "href").as_deref(), Some("#b"));
+        assert_eq!(attr(uses[1], xlink, "href").as_deref(), Some("#c"));
+        assert_eq!(attr(uses[1], "http://www.w3.org/XML/1998/namespace", "lang").as_deref(), Some("en"));
+        assert_eq!(attr(uses[1], "", "href"), None);
+        assert_eq!(tree.node(uses[1]).unwrap().get_attr_ns(xlink, "href", &HashMap::new()), None);
+        let scope = tree.in_scope_namespaces(uses[1]);
+        assert_eq!(scope.get("").map(|x| x.as_str()), Some("http://www.w3.org/2000/svg"));
+        assert_eq!(scope.len(), 3);
+    }
+    #[test]
+    fn test_can_append() {
+        let tree = ETree::parse_str("<!--c--><r><a><b><c/></b></a><!--k--><d/></r>");
+        let pos = |path:&str| tree.find(path).unwrap();
+        let (r, a, b, c, d) = (tree.root(), pos("/a"), pos("/a/b"), pos("//c"), pos("/d"));
+        let k = tree.children(r)[1];
+        assert!(tree.can_append_child(d, a));
+        assert!(tree.can_append_child(r, c));
+        assert!(!tree.can_append_child(a, a));
+        assert!(!tree.can_append_child(c, a));
+        assert!(!tree.can_append_child(b, b));
+        assert!(!tree.can_append_child(k, d));
+        assert!(!tree.can_append_child(99, d));
+        assert!(!tree.can_append_child(r, 99));
+        assert!(!tree.can_append_previous(a, 99));
+        assert!(tree.can_append_previous(a, d));
+        assert!(tree.can_append_previous(r, d));
+        assert!(!tree.can_append_previous(0, d));
+        assert!(!tree.can_append_previous(c, b));
+        assert!(tree.can_append_next(c, d));
+        assert!(!tree.can_append_next(b, a));
+        assert!(!tree.can_append_next(99, a));
+        assert!(!tree.can_append_next(d, 99));
+    }
+    #[test]
+    fn test_write_corrupt_tree() {
+        let mut tree = ETree::parse_str("<r><a><b/></a><c/></r>");
+        let b = tree.find("//b").unwrap();
+        tree.node_mut(b).unwrap().set_route("#9#");
+        match tree.write_with(&WriteOptions::default()) {
+            Err(ETreeError::CorruptTree { previous, index, route, .. }) => {
+                assert_eq!((previous, index), (b - 1, b));
+                assert_eq!(route, "#9#");
+            },
+            other => panic!("{:?}", other),
+        }
+        let mut tree = ETree::parse_str("<r><a><b/></a></r>");
+        let b = tree.find("//b").unwrap();
+        tree.node_mut(b).unwrap().set_route("#0#9#");
+        assert!(matches!(tree.write_with(&WriteOptions::default()), Err(ETreeError::CorruptTree { index:3, .. })));
+        let err = tree.write_file(std::env::temp_dir().join("etree_corrupt.xml")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+    #[test]
+    fn test_check_integrity() {
+        let mut tree = ETree::parse_str("<!--c--><r><a><b/><!--k--></a><c>t</c></r>");
+        assert_eq!(tree.check_integrity(), Ok(()));
+        let a = tree.find("/a").unwrap();
+        tree.append_child_tree(a, ETree::parse_str("<x><y/></x>"));
+        tree.remove(tree.find("//b").unwrap());
+        tree.compact();
+        assert_eq!(tree.check_integrity(), Ok(()));
+        tree.set_enable_index(false);
+        assert_eq!(tree.check_integrity(), Ok(()));
+        tree.set_enable_index(true);
+        let mut broken = tree.clone();
+        let c = broken.find("/c").unwrap();
+        broken.node_mut(c).unwrap().set_idx(0);
+        let errors = broken.check_integrity().unwrap_err();
+        assert!(errors.iter().any(|x| x == &format!("node {}: idx 0 is already used by node 0", c)));
+        assert!(errors.iter().any(|x| x.contains("index maps idx 0")));
+        let mut broken = ETree::parse_str("<r><a/><b/><c/></r>");
+        broken.node_mut(3).unwrap().set_route("#0#1#");
+        assert_eq!(broken.check_integrity(), Err(vec!["node 3: route #0#1# doesn't follow route #0# of node 2".to_string()]));
+        broken.node_mut(3).unwrap().set_route("#0#5#");
+        assert_eq!(broken.check_integrity().unwrap_err()[0], "node 3: parent idx 5 in route #0#5# is not found before the node");
+        let mut broken = tree.clone();
+        let k = broken.children(a)[0];
+        broken.node_mut(k + 1).unwrap().set_route(&format!("#1#2#{}#", k));
+        let errors = broken.check_integrity().unwrap_err();
+        assert!(errors[0].contains("is not an element"), "{:?}", errors);
+        let mut broken = tree.clone();
+        broken.node_mut(1).unwrap().set_route("#7");
+        assert!(broken.check_integrity().unwrap_err()[0].contains("malformed route"));
+    }
+    #[test]
+    fn test_normalized_text() {
+        let tree = ETree::parse_str("<r>\n  <a>\n    Hello,\t\r\n   world  \n  </a>\n  <b>x\u{a0} y</b><c/><d>t<e/>u</d>\n</r>");
+        assert_eq!(tree.normalized_text(tree.find("/a").unwrap()), "Hello, world");
+        assert_eq!(tree.normalized_text(tree.find("/b").unwrap()), "x\u{a0} y");
+        assert_eq!(tree.normalized_text(tree.find("/c").unwrap()), "");
+        assert_eq!(tree.normalized_text(tree.find("/d").unwrap()), "t");
+        assert_eq!(tree.normalized_text(tree.root()), "");
+        assert_eq!(tree.normalized_text(99), "");
+    }
+    #[test]
+    fn test_with_root() {
+        let svg = "http://www.w3.org/2000/svg";
+        let xlink = "http://www.w3.org/1999/xlink";
+        let mut root = ETreeNode::new("svg");
+        root.set_namespace(svg);
+        let mut tree = ETree::with_root(root, &[("", svg), ("xl", xlink)]);
+        let mut child = ETreeNode::new("g");
+        child.set_namespace(svg);
+        let g = tree.append_child_node(tree.root(), child).unwrap();
+        let mut child = ETreeNode::new("link");
+        child.set_namespace(xlink);
+        child.set_attr("xl:href", "#a");
+        tree.append_child_node(g, child);
+        assert_eq!(tree.namespace_uri("xl"), Some(xlink));
+        assert_eq!(tree.get_full_name(g).as_deref(), Some("g"));
+        assert!(tree.to_string().ends_with("<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xl=\"http://www.w3.org/1999/xlink\"><g><xl:link xl:href=\"#a\"/></g></svg>"));
+        assert_eq!(tree.find_ns_iter("//xl:link").count(), 1);
+    }
+    #[test]
+    fn test_closest() {
+        let tree = ETree::parse_str("<doc><table id=\"t1\"><row><table id=\"t2\"><row><cell/></row></table></row></table></doc>");
+        let cell = tree.find("//cell").unwrap();
+        let t2 = tree.find("//table[@id='t2']").unwrap();
+        assert_eq!(tree.closest(cell, "table"), Some(t2));
+        assert_eq!(tree.closest(t2, "table"), tree.find("/table"));
+        assert_eq!(tree.closest(cell, "doc"), Some(tree.root()));
+        assert_eq!(tree.closest(cell, "cell"), None);
+        assert_eq!(tree.closest(tree.root(), "doc"), None);
+        assert_eq!(tree.closest_by(cell, |x| x.get_attr("id").as_deref() == Some("t1")), tree.find("/table"));
+    }
+    #[test]
+    fn test_replace_text_all() {
+        let content = "<r title=\"secret\"><a>secret plan</a>secret<!--secret--><![CDATA[secret]]><b k=\"secret\"/><?pi secret?></r>";
+        let mut tree = ETree::parse_str(content);
+        assert_eq!(tree.replace_text_all("secret", "***", false), 2);
+        assert!(tree.to_string().ends_with("<r title=\"secret\"><a>*** plan</a>***<!--secret--><![CDATA[***]]><b k=\"secret\"/><?pi secret?></r>"));
+        let mut tree = ETree::parse_str(content);
+        assert_eq!(tree.replace_text_all("secret", "***", true), 4);
+        assert!(tree.to_string().ends_with("<r title=\"***\"><a>*** plan</a>***<!--secret--><![CDATA[***]]><b k=\"***\"/><?pi secret?></r>"));
+        assert_eq!(tree.replace_text_all("secret", "***", true), 0);
+        assert_eq!(tree.replace_text_all("", "x", true), 0);
+    }
+    #[test]
+    fn test_roundtrip_whitespace() {
+        let contents = [
+            "<?xml version=\"1.0\"?>\n<r>\n   <a>\n\t<b/>  \n </a>\n  <c x=\"1\">  t  </c>\r\n<d></d>\n\n</r>\n",
+            "<?xml version=\"1.0\"?>\r\n<!--c-->\r\n<r>\r\n    <a>x<b/> y </a>\r\n  <!-- k -->\t<![CDATA[ z ]]>\r\n</r>",
+            "<?xml version=\"1.0\"?><r><a><b>\n</b>\n\n\n      </a></r>",
+        ];
+        for content in contents {
+            let tree = ETree::parse_str(content);
+            assert_eq!(tree.to_string(), content);
+            assert_eq!(String::from_utf8(tree.write_with(&WriteOptions::default()).unwrap()).unwrap(), content);
+        }
+        let mut tree = ETree::parse_str(contents[2]);
+        tree.pretty("  ");
+        assert!(tree.to_string().starts_with("<?xml version=\"1.0\"?>\n<r>\n  <a>"));
+    }
+    #[test]
+    fn test_children_by_qname() {
+        let tree = ETree::parse_str("<r xmlns:a=\"urn:x\" xmlns:b=\"urn:x\" xmlns:c=\"urn:y\"><a:item>1</a:item><b:item>2</b:item><c:item>3</c:item><item>4</item><!--item--></r>");
+        let texts = |found:Vec<usize>| -> Vec<String> { found.into_iter().map(|x| tree.node(x).unwrap().get_text().unwrap()).collect() };
+        let root = tree.root();
+        assert_eq!(texts(tree.children_by_name(root, "a:item")), vec!["1"]);
+        assert_eq!(texts(tree.children_by_localname(root, "item")), vec!["1", "2", "3", "4"]);
+        assert_eq!(texts(tree.children_by_qname(root, "urn:x", "item")), vec!["1", "2"]);
+        assert_eq!(texts(tree.children_by_qname(root, "", "item")), vec!["4"]);
+        assert!(tree.children_by_qname(root, "urn:z", "item").is_empty());
+    }
+    #[test]
+    fn test_child_or_create() {
+        let mut tree = ETree::parse_str("<r><a/><config>1</config><config>2</config></r>");
+        let root = tree.root();
+        let pos = tree.child_or_create(root, "config").unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_text().unwrap(), "1");
+        let pos = tree.child_or_create(root, "extra").unwrap();
+        assert_eq!(tree.child_or_create(root, "extra"), Some(pos));
+        let pos = tree.child_or_create(pos, "item").unwrap();
+        tree.node_mut(pos).unwrap().set_text("x");
+        assert_eq!(tree.child_or_create(tree.data.len(), "config"), None);
+        let mut commented = ETree::parse_str("<!--c--><r/>");
+        assert_eq!(commented.child_or_create(0, "config"), None);
+        assert_eq!(tree.write_with(&WriteOptions::new().declaration(false)).unwrap(), b"<r><a/><config>1</config><config>2</config><extra><item>x</item></extra></r>".to_vec());
+    }
+    #[test]
+    fn test_set_attr_ns() {
+        let mut tree = ETree::parse_str("<r xmlns:xlink=\"http://www.w3.org/1999/xlink\"><a/></r>");
+        let opts = WriteOptions::new().declaration(false);
+        let root = tree.root();
+        let mut node = ETreeNode::new("img");
+        node.set_attr_ns("xlink", "http://www.w3.org/1999/xlink", "href", "a.png");
+        node.set_attr_ns("svg", "http://www.w3.org/2000/svg", "role", "icon");
+        node.set_attr_ns("xml", "http://www.w3.org/XML/1998/namespace", "lang", "en");
+        assert_eq!(node.get_attr_ns("http://www.w3.org/2000/svg", "role", &HashMap::new()).unwrap(), "icon");
+        let pos = tree.append_child_node(root, node.clone()).unwrap();
+        assert_eq!(String::from_utf8(tree.write_with(&opts).unwrap()).unwrap(),
+                   "<r xmlns:xlink=\"http://www.w3.org/1999/xlink\"><a/><img xlink:href=\"a.png\" svg:role=\"icon\" xml:lang=\"en\" xmlns:svg=\"http://www.w3.org/2000/svg\"/></r>");
+        assert_eq!(tree.node(pos).unwrap().get_attr_ns("http://www.w3.org/1999/xlink", "href", &tree.in_scope_namespaces(pos)).unwrap(), "a.png");
+        // the same prefix bound to another uri by an ancestor is declared again
+        let mut other = ETree::parse_str("<r xmlns:xlink=\"urn:other\"/>");
+        let root = other.root();
+        other.append_child_node(root, node.clone());
+        assert_eq!(String::from_utf8(other.write_with(&opts).unwrap()).unwrap(),
+                   "<r xmlns:xlink=\"urn:other\">\n<img xlink:href=\"a.png\" svg:role=\"icon\" xml:lang=\"en\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" xmlns:svg=\"http://www.w3.org/2000/svg\"/>\n</r>");
+        // a declaration on the node itself is updated
+        node.set_attr("xmlns:v", "urn:old");
+        node.set_attr_ns("v", "urn:new", "k", "1");
+        assert_eq!(node.get_attr("xmlns:v").unwrap(), "urn:new");
+        assert!(!node.get_pending_namespaces().iter().any(|x| x.0 == "v"));
+    }
+    #[test]
+    fn test_find_nth() {
+        let tree = ETree::parse_str("<r><a>1</a><b><a>2</a></b><a>3</a></r>");
+        let text = |pos:Option<usize>| pos.map(|x| tree.node(x).unwrap().get_text().unwrap());
+        assert_eq!(tree.find_nth("//a", 0), tree.find("//a"));
+        assert_eq!(text(tree.find_nth("//a", 2)).as_deref(), Some("3"));
+        assert_eq!(tree.find_nth("//a", 3), None);
+        assert_eq!(tree.rfind_nth("//a", 0), tree.rfind("//a"));
+        assert_eq!(text(tree.rfind_nth("/a", 1)).as_deref(), Some("1"));
+        assert_eq!(tree.rfind_nth("/a", 2), None);
+    }
+    #[test]
+    fn test_tag_names() {
+        let tree = ETree::parse_str("<?xml version=\"1.0\"?><!--c--><r xmlns:x=\"urn:x\"><b/><a><b/></a><x:b/><![CDATA[d]]><a/><?pi x?></r>");
+        let names = tree.tag_names();
+        let names:Vec<(&str, usize)> = names.iter().map(|(x, y)| (x.as_str(), *y)).collect();
+        assert_eq!(names, vec![("a", 2), ("b", 2), ("r", 1), ("x:b", 1)]);
+        assert!(ETree::empty().tag_names().is_empty());
+    }
+    #[test]
+    fn test_wrap_text_as_cdata() {
+        let mut tree = ETree::parse_str("<root><script>if (a &lt; b) {}</script></root>");
+        let pos = tree.find("//script").unwrap();
+        let cdata = tree.wrap_text_as_cdata(pos).unwrap();
+        assert_eq!(tree.node(cdata).unwrap().get_localname(), "<CData>");
+        assert_eq!(tree.node(pos).unwrap().get_text().as_deref(), Some(""));
+        let output = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(output.ends_with("<root><script><![CDATA[if (a < b) {}]]></script></root>"));
+        assert_eq!(tree.wrap_text_as_cdata(pos), None);
+    }
+}
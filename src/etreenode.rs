@@ -1,3 +1,22 @@
+use std::collections::HashMap;
+
+/// namespace bound to prefix `xml`
+const XML_NS:&str = "http://www.w3.org/XML/1998/namespace";
+
+/// value of a node which may contain entity references
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Slot {
+    Text,
+    Tail,
+    Attr(String),
+}
+
+/// Stable handle of a tree node
+///
+/// unlike position, it is not changed by inserting or removing other nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(pub(crate) usize);
+
 /// Element tree node
 ///
 /// `etree.ETreeNode` stores information of a tree node.
@@ -12,6 +31,7 @@
 /// - `attr`: key-value pairs in the open tag
 /// - `idx`: id for the node for internal useage
 /// - `route`: descendant route from root to parent for internal usage (format: `#root_idx#child_idx#child_child_idx#`)
+/// - `source_offset`: byte offset of `<` of the open tag in the parsed text, none for nodes which are not parsed elements
 ///
 /// For the following xml file:
 /// ```xml
@@ -47,15 +67,24 @@
 /// - `attr`: `[("base-package", "xxx.xxx.controller"), ]`
 ///
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ETreeNode {
+    #[cfg_attr(feature = "serde", serde(skip))]
     idx:usize,
     ns:String,
     ns_abbrev:String,
     local_name:String,
     attr:Vec<(String, String)>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pending_ns:Vec<(String, String)>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    entity_refs:Vec<(Slot, usize)>,
     text:Option<String>,
     tail:String,
+    #[cfg_attr(feature = "serde", serde(skip))]
     route:String,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    source_offset:Option<usize>,
 }
 
 impl ETreeNode {
@@ -67,20 +96,37 @@ impl ETreeNode {
             ns_abbrev:"".to_string(),
             local_name:String::from(localname),
             attr:Vec::new(),
+            pending_ns:Vec::new(),
+            entity_refs:Vec::new(),
             text:None,
             tail:"".to_string(),
             route:"".to_string(),
+            source_offset:None,
         }
     }
     #[allow(dead_code)]
+    pub fn comment(text:&str) -> ETreeNode {
+        let mut node = ETreeNode::new("<Comment>");
+        node.set_text(text);
+        node
+    }
+    #[allow(dead_code)]
     pub fn get_idx(&self) -> usize {
         self.idx
     }
     #[allow(dead_code)]
+    pub fn get_id(&self) -> NodeId {
+        NodeId(self.idx)
+    }
+    #[allow(dead_code)]
     pub fn get_route(&self) -> String {
         self.route.clone()
     }
     #[allow(dead_code)]
+    pub fn route_ref(&self) -> &str {
+        &self.route
+    }
+    #[allow(dead_code)]
     pub fn get_namespace(&self) -> String {
         self.ns.clone()
     }
@@ -94,25 +140,52 @@ impl ETreeNode {
     }
     #[allow(dead_code)]
     pub fn get_name(&self) -> String {
-        if self.ns_abbrev == "" {
-            format!("{}", self.local_name)
+        if self.ns_abbrev.is_empty() {
+            self.local_name.to_string()
         } else {
             format!("{}:{}", self.ns_abbrev, self.local_name)
         }
     }
     #[allow(dead_code)]
     pub fn get_localname(&self) -> String {
-        format!("{}", self.local_name)
+        self.local_name.to_string()
+    }
+    #[allow(dead_code)]
+    /// check for comment, CDATA, PI and DOCTYPE nodes, whose localname is `<Comment>`, `<CData>`, `<PI>` or `<DocType>`
+    pub fn is_special(&self) -> bool {
+        self.local_name.starts_with('<') && self.local_name.ends_with('>')
+    }
+    #[allow(dead_code)]
+    pub fn is_comment(&self) -> bool {
+        self.local_name == "<Comment>"
     }
     #[allow(dead_code)]
+    /// unescaped text, content of comment, CDATA, PI and DOCTYPE nodes is kept as is
+    ///
+    /// an unresolved reference to an entity declared in DOCTYPE is kept as `&name;` and written back as a reference,
+    /// while a literal `&amp;name;` is escaped again (see `ETree::set_resolve_entities`)
     pub fn get_text(&self) -> Option<String> {
         self.text.clone()
     }
     #[allow(dead_code)]
+    pub fn text_ref(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+    #[allow(dead_code)]
+    /// take text out of the node, entity references in it are dropped
+    pub fn take_text(&mut self) -> Option<String> {
+        self.drop_entity_refs(&Slot::Text);
+        self.text.take()
+    }
+    #[allow(dead_code)]
     pub fn get_tail(&self) -> String {
         self.tail.clone()
     }
     #[allow(dead_code)]
+    pub fn tail_ref(&self) -> &str {
+        &self.tail
+    }
+    #[allow(dead_code)]
     pub fn set_idx(&mut self, idx:usize) {
         self.idx = idx;
     }
@@ -121,6 +194,23 @@ impl ETreeNode {
         self.route = String::from(text);
     }
     #[allow(dead_code)]
+    pub fn get_source_offset(&self) -> Option<usize> {
+        self.source_offset
+    }
+    #[allow(dead_code)]
+    pub fn set_source_offset(&mut self, offset:Option<usize>) {
+        self.source_offset = offset;
+    }
+    #[allow(dead_code)]
+    /// 1-based line and column (in chars) of `source_offset` in `content`, which should be the parsed text
+    pub fn get_source_line_column(&self, content:&str) -> Option<(usize, usize)> {
+        let before = content.get(..self.source_offset?)?;
+        let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+        Some((before.matches('\n').count() + 1, before[line_start..].chars().count() + 1))
+    }
+    #[allow(dead_code)]
+    /// set namespace uri, also set a prefix with `set_namespace_abbrev` and declare it with an `xmlns:XXX` attr,
+    /// otherwise `ETree::write` uses a registered or default prefix and adds the declaration (see `ETree::get_full_name`)
     pub fn set_namespace(&mut self, text:&str) {
         self.ns = String::from(text);
     }
@@ -129,11 +219,15 @@ impl ETreeNode {
         self.ns_abbrev = String::from(text);
     }
     #[allow(dead_code)]
+    /// set literal text, entity references in the old text are dropped
     pub fn set_text(&mut self, text:&str) {
+        self.drop_entity_refs(&Slot::Text);
         self.text = Some(String::from(text));
     }
     #[allow(dead_code)]
+    /// set literal tail, entity references in the old tail are dropped
     pub fn set_tail(&mut self, text:&str) {
+        self.drop_entity_refs(&Slot::Tail);
         self.tail = String::from(text);
     }
     #[allow(dead_code)]
@@ -141,15 +235,23 @@ impl ETreeNode {
         self.attr.len()
     }
     #[allow(dead_code)]
-    pub fn get_attr_iter(&self) -> std::slice::Iter<(String, String)> {
+    pub fn get_attr_iter(&self) -> std::slice::Iter<'_, (String, String)> {
         self.attr.iter()
     }
     #[allow(dead_code)]
     pub fn get_attr(&self, key:&str) -> Option<String> {
-        self.find_attr(key).and_then(|idx| Some(self.attr[idx].1.clone()))
+        self.find_attr(key).map(|idx| self.attr[idx].1.clone())
+    }
+    #[allow(dead_code)]
+    /// mutable attr value, it is taken as literal and entity references in it are dropped
+    pub fn get_attr_mut(&mut self, key:&str) -> Option<&mut String> {
+        self.drop_entity_refs(&Slot::Attr(String::from(key)));
+        self.find_attr(key).map(move |idx| &mut self.attr[idx].1)
     }
     #[allow(dead_code)]
+    /// set literal attr value, entity references in the old value are dropped
     pub fn set_attr(&mut self, key:&str, value:&str) -> usize {
+        self.drop_entity_refs(&Slot::Attr(String::from(key)));
         if let Some(idx) = self.find_attr(key) {
             self.attr[idx].1 = String::from(value);
             idx
@@ -158,13 +260,166 @@ impl ETreeNode {
             self.attr.len()
         }
     }
-    fn find_attr(&self, key:&str) -> Option<usize> {
-        for i in 0..self.attr.len() {
-            if self.attr[i].0 == key {
-                return Some(i);
+    #[allow(dead_code)]
+    /// find a prefixed attr such as `xlink:href` by namespace uri and local name, attrs without prefix have no namespace
+    ///
+    /// the prefix is resolved by `xmlns:XXX` attrs of the node, then by `doc_ns` (e.g. `ETree::in_scope_namespaces`)
+    pub fn get_attr_ns(&self, uri:&str, local:&str, doc_ns:&HashMap<String, String>) -> Option<String> {
+        self.attr.iter().find(|(key, _)| {
+            match key.split_once(':') {
+                Some(("xmlns", _)) => false,
+                Some((prefix, name)) => name == local && self.find_attr(&format!("xmlns:{}", prefix))
+                    .map(|x| self.attr[x].1.as_str())
+                    .or_else(|| self.pending_ns.iter().find(|x| x.0 == prefix).map(|x| x.1.as_str()))
+                    .or_else(|| doc_ns.get(prefix).map(|x| x.as_str()))
+                    .or(if prefix == "xml" { Some(XML_NS) } else { None }) == Some(uri),
+                None => key == local && uri.is_empty(),
+            }
+        }).map(|x| x.1.clone())
+    }
+    #[allow(dead_code)]
+    /// set a prefixed attr such as `xlink:href`, an `xmlns:XXX` attr of the node is updated to `uri`,
+    /// otherwise the declaration is pending (see `get_pending_namespaces`) and added by `ETree::write` if needed
+    pub fn set_attr_ns(&mut self, prefix:&str, uri:&str, local:&str, value:&str) -> usize {
+        let decl = format!("xmlns:{}", prefix);
+        if self.find_attr(&decl).is_some() {
+            self.set_attr(&decl, uri);
+        } else if prefix != "xml" {
+            match self.pending_ns.iter_mut().find(|x| x.0 == prefix) {
+                Some(pending) => pending.1 = String::from(uri),
+                None => self.pending_ns.push((String::from(prefix), String::from(uri))),
             }
         }
-        return None;
+        self.set_attr(&format!("{}:{}", prefix, local), value)
+    }
+    #[allow(dead_code)]
+    pub fn get_pending_namespaces(&self) -> &[(String, String)] {
+        &self.pending_ns
+    }
+    /// byte offsets of unresolved entity references in the value of `slot` in ascending order
+    pub(crate) fn entity_refs(&self, slot:&Slot) -> Vec<usize> {
+        let mut refs:Vec<usize> = self.entity_refs.iter().filter(|x| &x.0 == slot).map(|x| x.1).collect();
+        refs.sort_unstable();
+        refs
+    }
+    /// record an entity reference starting at byte `offset` of the value of `slot`
+    pub(crate) fn add_entity_ref(&mut self, slot:&Slot, offset:usize) {
+        self.entity_refs.push((slot.clone(), offset));
+    }
+    /// replace recorded entity references with the values of `entities`
+    pub(crate) fn resolve_entity_refs(&mut self, entities:&HashMap<String, String>) {
+        let mut refs = std::mem::take(&mut self.entity_refs);
+        refs.sort();
+        for (slot, start) in refs.into_iter().rev() {
+            if let Some(value) = self.slot_mut(&slot) {
+                if let Some(end) = value.get(start..).and_then(|x| x.find(';')).map(|x| start + x) {
+                    if let Some(entity) = entities.get(&value[start+1..end]) {
+                        value.replace_range(start..end+1, entity);
+                    }
+                }
+            }
+        }
+    }
+    /// replace the value of `slot` by applying `f` to each literal part between entity references, references are kept,
+    /// `f` also gets whether the part is the first and the last one
+    pub(crate) fn map_literal<F:Fn(&str, bool, bool) -> String>(&mut self, slot:&Slot, f:F) {
+        let refs = self.entity_refs(slot);
+        let value = match self.slot_mut(slot) {
+            Some(value) => std::mem::take(value),
+            None => return,
+        };
+        let mut out = String::new();
+        let mut moved = Vec::new();
+        let mut last = 0;
+        for (i, &start) in refs.iter().enumerate() {
+            let end = match value.get(start..).and_then(|x| x.find(';')) {
+                Some(end) if start >= last => start + end + 1,
+                _ => continue,
+            };
+            out.push_str(&f(&value[last..start], i == 0, false));
+            moved.push(out.len());
+            out.push_str(&value[start..end]);
+            last = end;
+        }
+        out.push_str(&f(&value[last..], refs.is_empty(), true));
+        *self.slot_mut(slot).unwrap() = out;
+        self.drop_entity_refs(slot);
+        for offset in moved {
+            self.add_entity_ref(slot, offset);
+        }
+    }
+    /// append `value` with entity references at `refs` to the value of `slot`, a missing `text` is taken as empty
+    pub(crate) fn append_literal(&mut self, slot:&Slot, value:&str, refs:&[usize]) {
+        if *slot == Slot::Text && self.text.is_none() {
+            self.text = Some(String::new());
+        }
+        if let Some(current) = self.slot_mut(slot) {
+            let offset = current.len();
+            current.push_str(value);
+            for x in refs {
+                self.add_entity_ref(slot, offset + x);
+            }
+        }
+    }
+    fn slot_mut(&mut self, slot:&Slot) -> Option<&mut String> {
+        match slot {
+            Slot::Text => self.text.as_mut(),
+            Slot::Tail => Some(&mut self.tail),
+            Slot::Attr(key) => self.find_attr(key).map(move |idx| &mut self.attr[idx].1),
+        }
+    }
+    fn drop_entity_refs(&mut self, slot:&Slot) {
+        self.entity_refs.retain(|x| &x.0 != slot);
+    }
+    #[allow(dead_code)]
+    /// compare as `eq_unordered` and also the order of attrs
+    pub fn eq_ordered(&self, other:&ETreeNode) -> bool {
+        self.eq_content(other) && self.attr == other.attr
+    }
+    #[allow(dead_code)]
+    /// compare namespace, localname, attrs regardless of order, text, tail and entity references,
+    /// prefix, pending declarations, `idx`, `route` and `source_offset` are ignored
+    pub fn eq_unordered(&self, other:&ETreeNode) -> bool {
+        self.eq_content(other) && self.sorted_attr() == other.sorted_attr()
+    }
+    fn eq_content(&self, other:&ETreeNode) -> bool {
+        self.ns == other.ns
+            && self.local_name == other.local_name
+            && self.text == other.text
+            && self.tail == other.tail
+            && self.sorted_entity_refs() == other.sorted_entity_refs()
+    }
+    fn find_attr(&self, key:&str) -> Option<usize> {
+        self.attr.iter().position(|x| x.0 == key)
+    }
+    fn sorted_entity_refs(&self) -> Vec<&(Slot, usize)> {
+        let mut refs:Vec<&(Slot, usize)> = self.entity_refs.iter().collect();
+        refs.sort();
+        refs
+    }
+    fn sorted_attr(&self) -> Vec<&(String, String)> {
+        let mut attr:Vec<&(String, String)> = self.attr.iter().collect();
+        attr.sort();
+        attr
+    }
+}
+
+/// nodes are equal by `eq_unordered`
+impl PartialEq for ETreeNode {
+    fn eq(&self, other:&Self) -> bool {
+        self.eq_unordered(other)
+    }
+}
+
+impl Eq for ETreeNode {}
+
+impl std::hash::Hash for ETreeNode {
+    fn hash<H:std::hash::Hasher>(&self, state:&mut H) {
+        self.ns.hash(state);
+        self.local_name.hash(state);
+        self.text.hash(state);
+        self.tail.hash(state);
+        self.sorted_attr().hash(state);
     }
 }
 
@@ -5,6 +5,10 @@
 mod etreenode;
 mod etree;
 mod xpath;
+mod error;
+mod options;
 
-pub use self::etreenode::ETreeNode;
-pub use self::etree::{ETree, XPathIterator};
+pub use self::etreenode::{ETreeNode, NodeId};
+pub use self::etree::{ETree, XPathIterator, XPathValue, DiffOp, NodeEvent};
+pub use self::error::{ETreeError, XPathError};
+pub use self::options::{WriteOptions, ReadOptions};
@@ -0,0 +1,157 @@
+/// Options of `ETree::write_with`
+///
+/// default options write the tree as `ETree::write_file` does:
+/// XML declaration, self-closing empty elements, double quoted attrs in stored order on one line,
+/// line ending and indent of the tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteOptions {
+    pub(crate) declaration:bool,
+    pub(crate) self_close:bool,
+    pub(crate) quote:char,
+    pub(crate) sort_attrs:bool,
+    pub(crate) line_ending:Option<String>,
+    pub(crate) indent:Option<String>,
+    pub(crate) indent_cap:Option<usize>,
+    pub(crate) attr_wrap:Option<(usize, usize)>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            declaration:true,
+            self_close:true,
+            quote:'"',
+            sort_attrs:false,
+            line_ending:None,
+            indent:None,
+            indent_cap:None,
+            attr_wrap:None,
+        }
+    }
+}
+
+impl WriteOptions {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[allow(dead_code)]
+    /// write XML declaration or not
+    pub fn declaration(mut self, declaration:bool) -> Self {
+        self.declaration = declaration;
+        self
+    }
+    #[allow(dead_code)]
+    /// write elements without text as `<a/>`, otherwise as `<a></a>`
+    pub fn self_close(mut self, self_close:bool) -> Self {
+        self.self_close = self_close;
+        self
+    }
+    #[allow(dead_code)]
+    /// quote attr values with `'` instead of `"`
+    pub fn single_quote(mut self, single_quote:bool) -> Self {
+        self.quote = if single_quote { '\'' } else { '"' };
+        self
+    }
+    #[allow(dead_code)]
+    /// write attrs sorted by key
+    pub fn sort_attrs(mut self, sort_attrs:bool) -> Self {
+        self.sort_attrs = sort_attrs;
+        self
+    }
+    #[allow(dead_code)]
+    /// convert line endings to `crlf` as `ETree::set_line_ending` does
+    pub fn line_ending(mut self, crlf:&str) -> Self {
+        self.line_ending = Some(crlf.to_string());
+        self
+    }
+    #[allow(dead_code)]
+    /// format nodes according to indent as `ETree::pretty` does
+    pub fn indent(mut self, indent:&str) -> Self {
+        self.indent = Some(indent.to_string());
+        self
+    }
+    #[allow(dead_code)]
+    /// with `indent`, indent nodes deeper than `max_depth` as the nodes at `max_depth` as `ETree::pretty_capped` does
+    pub fn indent_cap(mut self, max_depth:usize) -> Self {
+        self.indent_cap = Some(max_depth);
+        self
+    }
+    #[allow(dead_code)]
+    /// write attrs of an element on separate lines if it has more than `max_attrs` attrs
+    /// or its open tag is wider than `max_width`, pass `usize::MAX` to disable either threshold
+    pub fn wrap_attrs(mut self, max_attrs:usize, max_width:usize) -> Self {
+        self.attr_wrap = Some((max_attrs, max_width));
+        self
+    }
+}
+
+/// Options of `ETree::parse_with`
+///
+/// default options parse the text as `ETree::parse_str_checked` does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadOptions {
+    pub(crate) trim:bool,
+    pub(crate) trim_text:bool,
+    pub(crate) resolve_entities:bool,
+    pub(crate) max_depth:usize,
+    pub(crate) max_nodes:usize,
+    pub(crate) encoding:Option<String>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            trim:false,
+            trim_text:false,
+            resolve_entities:false,
+            max_depth:usize::MAX,
+            max_nodes:usize::MAX,
+            encoding:None,
+        }
+    }
+}
+
+impl ReadOptions {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[allow(dead_code)]
+    /// discard whitespace-only text between elements as `ETree::parse_str_trimmed` does
+    pub fn trim(mut self, trim:bool) -> Self {
+        self.trim = trim;
+        self
+    }
+    #[allow(dead_code)]
+    /// let the reader trim whitespace around every text and drop whitespace-only text,
+    /// unlike `trim` this also applies to mixed content and `xml:space="preserve"`
+    pub fn trim_text(mut self, trim_text:bool) -> Self {
+        self.trim_text = trim_text;
+        self
+    }
+    #[allow(dead_code)]
+    /// resolve entities declared in DOCTYPE as `ETree::set_resolve_entities` does
+    pub fn resolve_entities(mut self, resolve_entities:bool) -> Self {
+        self.resolve_entities = resolve_entities;
+        self
+    }
+    #[allow(dead_code)]
+    /// fail when nodes are nested deeper than `max_depth`, top level nodes have depth 1
+    pub fn max_depth(mut self, max_depth:usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+    #[allow(dead_code)]
+    /// fail when there are more than `max_nodes` nodes
+    pub fn max_nodes(mut self, max_nodes:usize) -> Self {
+        self.max_nodes = max_nodes;
+        self
+    }
+    #[allow(dead_code)]
+    /// replace the encoding of XML declaration, e.g. when the text was decoded from another encoding
+    pub fn encoding(mut self, encoding:&str) -> Self {
+        self.encoding = Some(encoding.to_string());
+        self
+    }
+}
@@ -30,9 +30,12 @@
 ///     @name operator string
 ///     text() operator string
 ///     position() operator decimal
+///     position() operator last() - decimal
+///     position() operator last()
 ///     name
 ///     @name
 ///     @*
+///     not ( conditions_or )
 ///     ( condition )
 ///     ( conditions_and )
 ///     ( conditions_or )
@@ -54,12 +57,11 @@ use nom::{
     bytes::complete::{tag, escaped},
     character::complete::{one_of, none_of, char, anychar, space0, space1, alpha1, alphanumeric1, digit1},
     branch::alt,
-    sequence::{pair, tuple, delimited},
+    sequence::{pair, preceded, tuple, delimited},
     multi::{many0, many0_count},
     combinator::{recognize, opt, map, value},
 };
 
-#[allow(dead_code)]
 #[derive(Debug, PartialEq)]
 pub struct XPathSegment {
     pub separator: String,
@@ -67,11 +69,11 @@ pub struct XPathSegment {
     pub condition: Predictor,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, PartialEq)]
 pub enum Predictor {
     And(Box<Predictor>, Box<Predictor>),
     Or(Box<Predictor>, Box<Predictor>),
+    Not(Box<Predictor>),
     Condition(String, Option<String>, Option<String>),
     IndexDecimal(String),
     IndexExpr(String, String),
@@ -79,7 +81,6 @@ pub enum Predictor {
 }
 
 impl Predictor {
-    #[allow(dead_code)]
     pub fn collect(&self) -> (Vec<String>, Vec<String>, Vec<String>) {
         let mut child = HashSet::new();
         let mut attr = HashSet::new();
@@ -105,6 +106,12 @@ impl Predictor {
                 attr.extend(a2);
                 func.extend(f2);
             },
+            Predictor::Not(ref inner) => {
+                let (c1, a1, f1) = inner.collect();
+                child.extend(c1);
+                attr.extend(a1);
+                func.extend(f1);
+            },
             Predictor::Condition(ref left, _, _) => {
                 if left.starts_with("@") {
                     attr.insert(left.get(1..).unwrap().to_string());
@@ -127,55 +134,79 @@ impl Predictor {
         func.sort();
         (child, attr, func)
     }
-    #[allow(dead_code)]
-    pub fn expr(&self, info:&HashMap<String, String>) -> String {
+    /// evaluate the predicate with values of the current node in `info`
+    ///
+    /// `info` maps `@name`, `text()`, child names, `position()` and `last()` to values,
+    /// a condition on a key absent from `info` is false
+    pub fn eval(&self, info:&HashMap<String, String>) -> bool {
+        let number = |key:&str| info.get(key).and_then(|x| x.parse::<i64>().ok());
         match self {
-            Predictor::And(ref left, ref right) => {
-                format!("({}) && ({})", left.expr(info), right.expr(info))
-            },
-            Predictor::Or(ref left, ref right) => {
-                format!("({}) || ({})", left.expr(info), right.expr(info))
-            },
+            Predictor::And(ref left, ref right) => left.eval(info) && right.eval(info),
+            Predictor::Or(ref left, ref right) => left.eval(info) || right.eval(info),
+            Predictor::Not(ref inner) => !inner.eval(info),
             Predictor::Condition(ref left, ref op, ref right) => {
-                if info.contains_key(left) {
-                    if op.is_none() || right.is_none() {
-                        "true".to_string()
-                    } else {
-                        format!("'{}' {} {}", escape_info(info.get(left).unwrap()).unwrap().1, op.as_ref().unwrap(), right.as_ref().unwrap())
-                    }
-                } else {
-                    "false".to_string()
+                match (info.get(left), op, right) {
+                    (None, _, _) => false,
+                    (Some(_), Some(op), Some(right)) if left == "position()" => {
+                        debug_assert!(info.contains_key("last()"));
+                        let right = match right.strip_prefix("last()") {
+                            Some(offset) => {
+                                let offset = offset.trim().trim_start_matches('-').trim();
+                                number("last()").map(|x| x - offset.parse::<i64>().unwrap_or(0))
+                            },
+                            None => right.parse::<i64>().ok(),
+                        };
+                        matches!(right, Some(x) if compare(&info[left], op, &x.to_string()))
+                    },
+                    (Some(value), Some(op), Some(right)) => {
+                        let right = unescape_string(right).map(|x| x.1).unwrap_or_default();
+                        compare(value, op, &right)
+                    },
+                    _ => true,
                 }
             },
             Predictor::IndexDecimal(ref left) => {
                 debug_assert!(info.contains_key("position()"));
-                format!("{} == {}", info.get("position()").unwrap(), left)
+                matches!((number("position()"), left.parse::<i64>()), (Some(position), Ok(index)) if position == index)
             },
             Predictor::IndexExpr(ref left, ref right) => {
                 debug_assert!(info.contains_key("position()"));
                 debug_assert!(info.contains_key("last()"));
-                if right == "" {
-                    format!("{} == {}", info.get("position()").unwrap(), info.get(left).unwrap())
-                } else {
-                    format!("{} == {} - {}", info.get("position()").unwrap(), info.get(left).unwrap(), right)
-                }
+                let offset = if right.is_empty() { 0 } else { right.parse().unwrap_or(0) };
+                matches!((number("position()"), number(left)), (Some(position), Some(last)) if position == last - offset)
             },
-            _ => {
-                "true".to_string()
-            }
+            Predictor::None => true,
         }
     }
 }
 
-fn escape_info(input:&str) -> IResult<&str, String> {
+/// value of quoted `string` with `\\` escapes removed
+fn unescape_string(input:&str) -> IResult<&str, String> {
     map(
-        many0(alt((
-            value("\\\\".to_string(), char('\\')),
-            value("\\'".to_string(), char('\'')),
-            map(anychar, |c| c.to_string()),
-        ))), |v| v.join("")
+        delimited(
+            char('\''),
+            many0(alt((preceded(char('\\'), anychar), none_of("'\\")))),
+            char('\''),
+        ), |v| v.into_iter().collect()
     )(input)
 }
+/// `=` and `!=` compare strings, other operators compare numbers and are false for non-numeric values
+fn compare(left:&str, op:&str, right:&str) -> bool {
+    match op {
+        "==" => left == right,
+        "!=" => left != right,
+        _ => match (left.trim().parse::<f64>(), right.trim().parse::<f64>()) {
+            (Ok(left), Ok(right)) => match op {
+                ">=" => left >= right,
+                "<=" => left <= right,
+                ">" => left > right,
+                "<" => left < right,
+                _ => false,
+            },
+            _ => false,
+        },
+    }
+}
 fn decimal(input:&str) -> IResult<&str, &str> {
     digit1(input)
 }
@@ -221,12 +252,21 @@ fn index(input:&str) -> IResult<&str, Predictor> {
     ))(input)
 }
 
+fn position(input:&str) -> IResult<&str, String> {
+    alt((
+            map(decimal, |t| t.to_string()),
+            map(tuple((tag("last()"), space0, tag("-"), space0, decimal)), |t| format!("{} - {}", t.0, t.4)),
+            map(tag("last()"), |t:&str| t.to_string()),
+    ))(input)
+}
+
 fn condition(input:&str) -> IResult<&str, Predictor> {
     alt((
+            map(tuple((tag("not"), space0, tag("("), space0, conditions_or, space0, tag(")"))), |t| Predictor::Not(Box::new(t.4))),
             map(tuple((name, space0, operator, space0, string)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4.to_string()))),
             map(tuple((tag("@"), name, space0, operator, space0, string)), |t| Predictor::Condition(format!("@{}", t.1), Some(t.3.to_string()), Some(t.5.to_string()))),
             map(tuple((tag("text()"), space0, operator, space0, string)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4.to_string()))),
-            map(tuple((tag("position()"), space0, operator, space0, decimal)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4.to_string()))),
+            map(tuple((tag("position()"), space0, operator, space0, position)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4))),
             map(name, |t| Predictor::Condition(t.to_string(), None, None)),
             map(pair(tag("@"), name), |t| Predictor::Condition(format!("{}{}", t.0, t.1), None, None)),
             map(tag("@*"), |t:&str| Predictor::Condition(t.to_string(), None, None)),
@@ -264,8 +304,8 @@ fn element(input:&str) -> IResult<&str, XPathSegment> {
             }),
             map(recognize(pair(tag("@"), name)), |t| XPathSegment {
                 separator: "".to_string(),
-                node: "*".to_string(),
-                condition: Predictor::Condition(t.to_string(), None, None),
+                node: t.to_string(),
+                condition: Predictor::None,
             }),
             map(tuple((name, tag("["), space0, conditions_or, space0, tag("]"))), |t| XPathSegment {
                 separator: "".to_string(),
@@ -300,7 +340,6 @@ fn element(input:&str) -> IResult<&str, XPathSegment> {
     ))(input)
 }
 
-#[allow(dead_code)]
 pub fn xpath(input:&str) -> IResult<&str, Vec<XPathSegment>> {
     let (remaining, initial) = opt(element)(input)?;
     let mut segments = Vec::new();
@@ -363,7 +402,11 @@ mod tests {
         assert_eq!(condition("@attr  = 'aa'"), Ok(("", Predictor::Condition("@attr".to_string(), Some("==".to_string()), Some("'aa'".to_string())))));
         assert_eq!(condition("text()!= 'aa'"), Ok(("", Predictor::Condition("text()".to_string(), Some("!=".to_string()), Some("'aa'".to_string())))));
         assert_eq!(condition("position()>= 7a"), Ok(("a", Predictor::Condition("position()".to_string(), Some(">=".to_string()), Some("7".to_string())))));
+        assert_eq!(condition("position()>last()- 2"), Ok(("", Predictor::Condition("position()".to_string(), Some(">".to_string()), Some("last() - 2".to_string())))));
+        assert_eq!(condition("position() = last()"), Ok(("", Predictor::Condition("position()".to_string(), Some("==".to_string()), Some("last()".to_string())))));
         assert_eq!(condition("(position()>= 7 )a"), Ok(("a", Predictor::Condition("position()".to_string(), Some(">=".to_string()), Some("7".to_string())))));
+        assert_eq!(condition("not( @attr )a"), Ok(("a", Predictor::Not(Box::new(Predictor::Condition("@attr".to_string(), None, None))))));
+        assert_eq!(condition("not"), Ok(("", Predictor::Condition("not".to_string(), None, None))));
     }
     #[test]
     fn test_conditions_or() {
@@ -386,8 +429,20 @@ mod tests {
         assert_eq!(xpath("@id"), Ok(("", vec![
                     XPathSegment {
                         separator:"".to_string(),
-                        node:"*".to_string(),
-                        condition:Predictor::Condition("@id".to_string(), None, None)
+                        node:"@id".to_string(),
+                        condition:Predictor::None
+                    },
+        ])));
+        assert_eq!(xpath("//bean/@id"), Ok(("", vec![
+                    XPathSegment {
+                        separator:"//".to_string(),
+                        node:"bean".to_string(),
+                        condition:Predictor::None
+                    },
+                    XPathSegment {
+                        separator:"/".to_string(),
+                        node:"@id".to_string(),
+                        condition:Predictor::None
                     },
         ])));
         assert_eq!(xpath("//NODE[@oid and @attrcatref='abc']"), Ok(("", vec![
@@ -419,7 +474,7 @@ mod tests {
         ])));
     }
     #[test]
-    fn test_predictor_expr() {
+    fn test_predictor_eval() {
         let (remaining, segs) = xpath(".//NAME[text()='aa' and (@id='bb' or @gid)]").unwrap();
         assert_eq!(remaining, "");
         assert_eq!(segs.len(), 2);
@@ -431,11 +486,31 @@ mod tests {
         let mut info = HashMap::new();
         info.insert("text()".to_string(), "aaa".to_string());
         info.insert("@id".to_string(), "123".to_string());
-        assert_eq!(segs[1].condition.expr(&info), "('aaa' == 'aa') && (('123' == 'bb') || (false))")
+        assert!(!segs[1].condition.eval(&info));
+        info.insert("text()".to_string(), "aa".to_string());
+        assert!(!segs[1].condition.eval(&info));
+        info.insert("@gid".to_string(), "".to_string());
+        assert!(segs[1].condition.eval(&info));
+        let (_, segs) = xpath("a[position() > last() - 2 and not(position() = 5)]").unwrap();
+        let mut info = HashMap::new();
+        info.insert("last()".to_string(), "5".to_string());
+        let matched:Vec<bool> = (1..=5).map(|x| {
+            info.insert("position()".to_string(), x.to_string());
+            segs[0].condition.eval(&info)
+        }).collect();
+        assert_eq!(matched, vec![false, false, false, true, false]);
+    }
+    #[test]
+    fn test_unescape_string() {
+        assert_eq!(unescape_string("'ab\\'c'"), Ok(("", "ab'c".to_string())));
+        assert_eq!(unescape_string("'ab\\\\c'd"), Ok(("d", "ab\\c".to_string())));
     }
     #[test]
-    fn test_escape_info() {
-        assert_eq!(escape_info("ab'c"), Ok(("", "ab\\'c".to_string())));
-        assert_eq!(escape_info("ab\\c"), Ok(("", "ab\\\\c".to_string())));
+    fn test_compare() {
+        assert!(compare("a'b", "==", "a'b"));
+        assert!(compare("a", "!=", "b"));
+        assert!(compare("10", ">", "9"));
+        assert!(compare(" 1.5", "<=", "2"));
+        assert!(!compare("b", ">", "a"));
     }
 }